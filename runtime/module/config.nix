@@ -37,6 +37,12 @@
             echo "waiting to fail if the key is removed..."
 
             while inotifywait -e delete_self "${value.path}"; do
+              # wire replaces keys by renaming a new file over them
+              if [ -e "${value.path}" ]; then
+                echo "key was replaced, waiting again..."
+                continue
+              fi
+
               MSG="Key ${value.path} no longer exists."
 
               systemd-notify --status="$MSG"
@@ -43,12 +43,53 @@ in
             default = 22;
             description = "SSH port to use.";
           };
+          identityFile = lib.mkOption {
+            type = types.nullOr types.str;
+            default = null;
+            description = "Private key to log in with, as a string so that it is not copied to the Nix store.";
+            example = "~/.ssh/id_ed25519_deploy";
+          };
+          proxyJump = lib.mkOption {
+            type = types.listOf types.str;
+            default = [ ];
+            description = "Bastions to connect through, in order. Accepts anything `ssh -J` does, such as `user@host:port`.";
+            example = [ "bastion.example.com" ];
+          };
+          sshOptions = lib.mkOption {
+            type = types.listOf types.str;
+            default = [ ];
+            description = "Extra options passed to ssh with `-o`. They cannot override the options wire sets itself.";
+            example = [ "ServerAliveInterval=10" ];
+          };
+          knownHostsFile = lib.mkOption {
+            type = types.nullOr types.str;
+            default = null;
+            description = "File to read and record host keys in, instead of `~/.ssh/known_hosts`. Ignored when `hostKeys` is set.";
+          };
+          hostKeys = lib.mkOption {
+            type = types.listOf types.str;
+            default = [ ];
+            description = "Public host keys the node must present. When set, wire refuses to connect to hosts presenting any other key, even on first contact or with `--ssh-accept-host`.";
+            example = [ "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIHjN6eK1u0SxiM7h6QwoTbfxyiJ6KPFB/GwiiBdAGsIx" ];
+          };
         };
       };
       description = "Describes the target for this node";
       default = { };
     };
 
+    privilegeEscalation = lib.mkOption {
+      type = types.either (types.enum [
+        "sudo"
+        "doas"
+        "run0"
+        "none"
+      ]) types.str;
+      default = "sudo";
+      description = "How wire runs commands as root on the node. Any other string is used as a command prefix, such as `sudo -n`. Escalation is skipped when `deployment.target.user` is root.";
+      example = "doas";
+    };
+
     buildOnTarget = lib.mkOption {
       type = types.bool;
       default = false;
@@ -62,6 +103,82 @@ in
       node's name.";
     };
 
+    magicRollback = lib.mkOption {
+      type = types.submodule {
+        options = {
+          enable = lib.mkOption {
+            type = types.bool;
+            default = false;
+            description = "Whether the node should switch back to its previous generation if wire cannot confirm the activation. Only applies to the `switch` and `test` goals on remote nodes.";
+          };
+          timeout = lib.mkOption {
+            type = types.ints.positive;
+            default = 120;
+            description = "Seconds wire has to activate the node, reconnect, and pass every health check before the node rolls itself back. This includes the time spent running `switch-to-configuration`.";
+          };
+          healthChecks = lib.mkOption {
+            type = types.listOf types.str;
+            default = [ ];
+            description = "Commands ran on the node over a fresh SSH connection after activation. If any of them fail the node is rolled back.";
+            example = [
+              "systemctl is-system-running --wait"
+              "curl --fail http://localhost:8080/health"
+            ];
+          };
+        };
+      };
+      description = "Automatically roll back nodes that become unreachable or unhealthy after activation.";
+      default = { };
+    };
+
+    timeouts = lib.mkOption {
+      type = types.submodule {
+        options = lib.genAttrs [ "ping" "keys" "push" "build" "activate" ] (
+          kind:
+          lib.mkOption {
+            type = types.nullOr types.ints.positive;
+            default = null;
+            description = "Seconds a ${kind} step may take before it fails. `null` waits indefinitely.";
+          }
+        );
+      };
+      description = "Timeouts for each kind of step. `push` also covers pushing the key agent, and `activate` covers `switch-to-configuration` and rollbacks.";
+      default = { };
+      example = {
+        ping = 10;
+        build = 3600;
+      };
+    };
+
+    retries = lib.mkOption {
+      type = types.submodule {
+        options = lib.genAttrs [ "ping" "keys" "push" ] (
+          kind:
+          lib.mkOption {
+            type = types.ints.unsigned;
+            default = 0;
+            description = "Times a failed ${kind} step is attempted again.";
+          }
+        );
+      };
+      description = "Retries for steps that are safe to repeat. Each retry waits twice as long as the previous one, starting at one second and up to 30 seconds.";
+      default = { };
+      example = {
+        ping = 3;
+        push = 2;
+      };
+    };
+
+    dependsOn = lib.mkOption {
+      type = types.listOf types.str;
+      default = [ ];
+      description = "Nodes, or `@` prefixed tags, that must be applied successfully before this node is applied. If any of them fail, this node is skipped. Only nodes selected in the same invocation are waited on.";
+      example = [
+        "db-primary"
+        "@database"
+      ];
+    };
+
     tags = lib.mkOption {
       type = types.listOf types.str;
       default = [ ];
@@ -130,18 +247,28 @@ in
               group = lib.mkOption {
                 type = types.str;
                 default = "root";
-                description = "Group to own the key. If this group does not exist this will silently fail and the key will be owned by gid 0.";
+                description = "Group to own the key. If this group does not exist the key fails, unless `strictOwnership` is disabled.";
               };
               user = lib.mkOption {
                 type = types.str;
                 default = "root";
-                description = "User to own the key. If this user does not exist this will silently fail and the key will be owned by uid 0.";
+                description = "User to own the key. If this user does not exist the key fails, unless `strictOwnership` is disabled.";
               };
               permissions = lib.mkOption {
                 type = types.str;
                 default = "0600";
                 description = "Unix Octal permissions, in string format, for the key.";
               };
+              strictOwnership = lib.mkOption {
+                type = types.bool;
+                default = true;
+                description = "Fail the key if its `user` or `group` does not exist on the node. When disabled, the key is owned by uid or gid 0 instead.";
+              };
+              destDirPermissions = lib.mkOption {
+                type = types.str;
+                default = "0755";
+                description = "Unix Octal permissions, in string format, for the directories created for the key. Created directories are owned by the key's `user` and `group`, existing directories are left unchanged.";
+              };
               source = lib.mkOption {
                 type = types.oneOf [
                   types.str
@@ -22,6 +22,7 @@ makeHive {
         # Test defaulting to root when user or group does not exist
         user = "USERDOESNOTEXIST";
         group = "USERDOESNOTEXIST";
+        strictOwnership = false;
       };
       command = {
         source = [
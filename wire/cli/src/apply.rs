@@ -1,35 +1,45 @@
 // SPDX-License-Identifier: AGPL-3.0-or-later
 // Copyright 2024-2025 wire Contributors
 
-use futures::{FutureExt, StreamExt};
+use futures::StreamExt;
 use itertools::{Either, Itertools};
-use lib::hive::node::{Context, GoalExecutor, Name, StepState, should_apply_locally};
+use lib::hive::journal::Journal;
+use lib::hive::node::{Context, Goal, GoalExecutor, Name, Node, StepState, should_apply_locally};
+use lib::hive::plan::Plan;
+use lib::hive::policy::StepPolicyOverrides;
+use lib::hive::steps::diff::DiffMode;
 use lib::hive::{Hive, HiveLocation};
 use lib::{SubCommandModifiers, errors::HiveLibError};
 use miette::{Diagnostic, IntoDiagnostic, Result};
-use std::collections::HashSet;
-use std::io::Read;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::io::{IsTerminal, Read};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
 use thiserror::Error;
-use tracing::{Span, error, info};
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+use tracing::{Span, error, info, warn};
 
-use crate::cli::{ApplyArgs, ApplyTarget};
+use crate::cli::{ApplyArgs, ApplyTarget, OutputFormat, RollbackArgs};
+use crate::events::{Event, emit};
+use crate::rollout::{Rollout, Wave};
 
 #[derive(Debug, Error, Diagnostic)]
 #[error("node {} failed to apply", .0)]
-struct NodeError(
-    Name,
+pub struct NodeError(
+    pub Name,
     #[source]
     #[diagnostic_source]
-    HiveLibError,
+    pub HiveLibError,
 );
 
 #[derive(Debug, Error, Diagnostic)]
 #[error("{} node(s) failed to apply.", .0.len())]
-struct NodeErrors(#[related] Vec<NodeError>);
+pub struct NodeErrors(#[related] pub Vec<NodeError>);
 
-// returns Names and Tags
-fn read_apply_targets_from_stdin() -> Result<(Vec<String>, Vec<Name>)> {
+fn read_apply_targets_from_stdin() -> Result<Vec<ApplyTarget>> {
     let mut buf = String::new();
     let mut stdin = std::io::stdin().lock();
     stdin.read_to_string(&mut buf).into_diagnostic()?;
@@ -37,63 +47,393 @@ fn read_apply_targets_from_stdin() -> Result<(Vec<String>, Vec<Name>)> {
     Ok(buf
         .split_whitespace()
         .map(|x| ApplyTarget::from(x.to_string()))
-        .fold((Vec::new(), Vec::new()), |(mut tags, mut names), target| {
-            match target {
-                ApplyTarget::Node(name) => names.push(name),
-                ApplyTarget::Tag(tag) => tags.push(tag),
-                ApplyTarget::Stdin => {}
-            }
-            (tags, names)
-        }))
+        .filter(|target| !matches!(target, ApplyTarget::Stdin))
+        .collect())
+}
+
+/// Why a selected node was never applied
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SkipReason {
+    /// `--fail-fast` cancelled the apply after a node failed
+    Cancelled,
+    /// More than `--max-failures` nodes failed
+    TooManyFailures,
+    /// A node of the `--canary` wave failed
+    CanaryFailed,
+}
+
+impl SkipReason {
+    /// Why no more nodes are scheduled, if they are not
+    fn of(
+        rollout: &Rollout,
+        cancellation: &CancellationToken,
+        failures: &AtomicUsize,
+        canary_failed: &AtomicBool,
+    ) -> Option<Self> {
+        if cancellation.is_cancelled() {
+            Some(SkipReason::Cancelled)
+        } else if canary_failed.load(Ordering::SeqCst) {
+            Some(SkipReason::CanaryFailed)
+        } else if rollout.budget_exceeded(failures.load(Ordering::SeqCst)) {
+            Some(SkipReason::TooManyFailures)
+        } else {
+            None
+        }
+    }
+}
+
+impl Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SkipReason::Cancelled => write!(f, "as --fail-fast cancelled the apply"),
+            SkipReason::TooManyFailures => write!(f, "as too many nodes failed"),
+            SkipReason::CanaryFailed => write!(f, "as a canary failed"),
+        }
+    }
+}
+
+/// What became of a selected node, `None` in the watch channel until it
+/// finished
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Outcome {
+    Applied,
+    Failed,
+    Skipped(SkipReason),
+}
+
+/// Arguments shared by every subcommand that runs a goal against nodes
+#[allow(clippy::struct_excessive_bools)]
+struct Execution {
+    goal: Goal,
+    on: Vec<ApplyTarget>,
+    parallel: usize,
+    no_keys: bool,
+    force_keys: bool,
+    no_prune_keys: bool,
+    reboot: bool,
+    diff: DiffMode,
+    output: OutputFormat,
+    rollout: Rollout,
+    fail_fast: bool,
+    overrides: StepPolicyOverrides,
+    journal: Option<Arc<Journal>>,
+    plan: Option<Plan>,
+}
+
+impl Execution {
+    /// Seeds the state of planned nodes with their planned toplevel
+    fn state(&self, name: &Name) -> StepState {
+        StepState {
+            evaluation: self
+                .plan
+                .as_ref()
+                .and_then(|plan| plan.nodes.get(name))
+                .and_then(|node| node.derivation.clone()),
+            ..Default::default()
+        }
+    }
+
+    fn executor<'a>(
+        &self,
+        context: Context<'a>,
+        cancellation: &CancellationToken,
+    ) -> GoalExecutor<'a> {
+        let executor = GoalExecutor::new(context)
+            .cancelled_by(cancellation.clone())
+            .with_overrides(self.overrides.clone());
+
+        match &self.journal {
+            Some(journal) => executor.journaled(journal.clone()),
+            None => executor,
+        }
+    }
+}
+
+/// Nodes selected by `--on`
+pub struct Selection {
+    everything: bool,
+    include: Vec<ApplyTarget>,
+    exclude: Vec<ApplyTarget>,
+}
+
+impl Selection {
+    /// Resolves `--on`, reading additional targets from stdin if `-` was
+    /// passed.
+    ///
+    /// Negated targets exclude nodes from those selected by the others, or
+    /// from every node if there are no others.
+    pub fn new(on: &[ApplyTarget], modifiers: &mut SubCommandModifiers) -> Self {
+        let (include, exclude) = on
+            .iter()
+            .flat_map(|target| match target {
+                ApplyTarget::Stdin => {
+                    // implies non_interactive
+                    modifiers.non_interactive = true;
+
+                    read_apply_targets_from_stdin().unwrap()
+                }
+                target => vec![target.clone()],
+            })
+            .partition_map::<Vec<_>, Vec<_>, _, _, _>(|target| match target {
+                ApplyTarget::Not(target) => Either::Right(*target),
+                target => Either::Left(target),
+            });
+
+        Selection {
+            everything: include.is_empty() && (on.is_empty() || !exclude.is_empty()),
+            include,
+            exclude,
+        }
+    }
+
+    pub fn contains(&self, name: &Name, node: &Node) -> bool {
+        self.contains_tagged(name, &|tag| node.tags.contains(tag))
+    }
+
+    fn contains_tagged(&self, name: &Name, has_tag: &dyn Fn(&str) -> bool) -> bool {
+        (self.everything
+            || self
+                .include
+                .iter()
+                .any(|target| target.matches_tagged(name, has_tag)))
+            && !self
+                .exclude
+                .iter()
+                .any(|target| target.matches_tagged(name, has_tag))
+    }
+
+    /// Names of the selected nodes, sorted
+    pub fn resolve<'a>(&self, hive: &'a Hive) -> Vec<&'a Name> {
+        hive.nodes
+            .iter()
+            .filter(|(name, node)| self.contains(name, node))
+            .map(|(name, _)| name)
+            .sorted()
+            .collect()
+    }
 }
 
-// #[instrument(skip_all, fields(goal = %args.goal, on = %args.on.iter().join(", ")))]
+// #[instrument(skip_all, fields(goal = %args.goal, on = %args.targets.on.iter().join(", ")))]
 pub async fn apply(
     hive: &mut Hive,
     location: HiveLocation,
     args: ApplyArgs,
     mut modifiers: SubCommandModifiers,
 ) -> Result<()> {
-    let header_span = Span::current();
-    let location = Arc::new(location);
-
     // Respect user's --always-build-local arg
     hive.force_always_local(args.always_build_local)?;
 
-    let header_span_enter = header_span.enter();
-
-    let (tags, names) = args.on.iter().fold(
-        (HashSet::new(), HashSet::new()),
-        |(mut tags, mut names), target| {
-            match target {
-                ApplyTarget::Tag(tag) => {
-                    tags.insert(tag.clone());
-                }
-                ApplyTarget::Node(name) => {
-                    names.insert(name.clone());
-                }
-                ApplyTarget::Stdin => {
-                    // implies non_interactive
-                    modifiers.non_interactive = true;
+    let plan = match &args.plan {
+        Some(path) => {
+            let plan = Plan::read(path)?;
 
-                    let (found_tags, found_names) = read_apply_targets_from_stdin().unwrap();
-                    names.extend(found_names);
-                    tags.extend(found_tags);
-                }
+            if plan.nodes.is_empty() {
+                miette::bail!("{} does not plan any nodes", path.display());
             }
-            (tags, names)
+
+            plan.verify(path, &location, &hive.nodes, modifiers).await?;
+            info!("Applying the plan in {}", path.display());
+
+            Some(plan)
+        }
+        None => None,
+    };
+
+    let (goal, on, no_keys) = match &plan {
+        Some(plan) => (
+            plan.goal,
+            plan.nodes
+                .keys()
+                .map(|name| ApplyTarget::Node(name.clone()))
+                .collect(),
+            plan.no_keys,
+        ),
+        None => (args.goal.try_into()?, args.targets.on, args.no_keys),
+    };
+
+    // stdin can only be read once
+    let reads_stdin = |targets: &[ApplyTarget]| {
+        targets
+            .iter()
+            .any(|target| matches!(target, ApplyTarget::Stdin))
+    };
+
+    if reads_stdin(&on) && reads_stdin(&args.canary) {
+        miette::bail!("`-` can only be passed to one of `--on` and `--canary`");
+    }
+
+    // a `--non-interactive` before `apply` is not caught by clap. Without a
+    // terminal on stdin it is the default, and `--confirm` prompts on the
+    // controlling terminal instead, as stdin may be read by `--on -`
+    if args.confirm && modifiers.non_interactive && std::io::stdin().is_terminal() {
+        miette::bail!("`--confirm` cannot be used with `--non-interactive`");
+    }
+
+    // only `--resume` requires a journal
+    let journal = match Journal::open(&location, goal, args.resume) {
+        Ok(journal) => Some(Arc::new(journal)),
+        Err(err) if !args.resume => {
+            warn!("Not recording this apply to a journal: {err}");
+            None
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    let execution = Execution {
+        goal,
+        on,
+        parallel: args.parallel,
+        no_keys,
+        force_keys: args.force_keys,
+        no_prune_keys: args.no_prune_keys,
+        reboot: args.reboot,
+        diff: match (args.diff, args.confirm) {
+            (_, true) => DiffMode::Confirm,
+            (true, false) => DiffMode::Show,
+            (false, false) => DiffMode::Disabled,
+        },
+        output: args.output,
+        rollout: Rollout {
+            canary: (!args.canary.is_empty()).then(|| Selection::new(&args.canary, &mut modifiers)),
+            batch_size: args.batch_size,
+            max_failures: args.max_failures,
+            pause: args.batch_pause.map(Duration::from_secs),
+        },
+        fail_fast: args.fail_fast,
+        overrides: StepPolicyOverrides {
+            timeouts: args.timeout.into_iter().collect(),
+            retries: args.retries.into_iter().collect(),
         },
-    );
+        journal,
+        plan,
+    };
 
-    let mut set = hive
-        .nodes
-        .iter_mut()
-        .filter(|(name, node)| {
-            args.on.is_empty()
-                || names.contains(name)
-                || node.tags.iter().any(|tag| tags.contains(tag))
-        })
-        .map(|(name, node)| {
+    execute(hive, location, execution, modifiers).await
+}
+
+pub async fn rollback(
+    hive: &mut Hive,
+    location: HiveLocation,
+    args: RollbackArgs,
+    modifiers: SubCommandModifiers,
+) -> Result<()> {
+    let execution = Execution {
+        goal: Goal::Rollback(args.generation),
+        on: args.targets.on,
+        parallel: args.parallel,
+        no_keys: true,
+        force_keys: false,
+        no_prune_keys: true,
+        reboot: false,
+        diff: DiffMode::Disabled,
+        output: OutputFormat::Human,
+        rollout: Rollout::default(),
+        fail_fast: false,
+        overrides: StepPolicyOverrides::default(),
+        journal: None,
+        plan: None,
+    };
+
+    execute(hive, location, execution, modifiers).await
+}
+
+/// Waits for each selected dependency of `name` to finish. Returns why the node
+/// is skipped, or the error it fails with, if a dependency did not apply.
+async fn await_dependencies<'a>(
+    name: &Name,
+    dependencies: impl Iterator<Item = &'a Name>,
+    outcomes: &HashMap<Name, watch::Sender<Option<Outcome>>>,
+    skip_reason: impl Fn() -> Option<SkipReason>,
+) -> Option<Either<SkipReason, HiveLibError>> {
+    for dependency in dependencies {
+        let mut receiver = outcomes[dependency].subscribe();
+        let outcome = receiver
+            .wait_for(Option::is_some)
+            .await
+            .map(|outcome| *outcome);
+
+        let reason = match outcome {
+            Ok(Some(Outcome::Applied)) => continue,
+            Ok(Some(Outcome::Skipped(reason))) => Some(reason),
+            // the dependency failed, possibly cancelled by `--fail-fast`
+            _ => skip_reason(),
+        };
+
+        return Some(reason.map_or_else(
+            || {
+                Either::Right(HiveLibError::DependencyFailed {
+                    name: name.clone(),
+                    dependency: dependency.clone(),
+                })
+            },
+            Either::Left,
+        ));
+    }
+
+    None
+}
+
+/// Applies each wave in turn, returning the result of every node that was
+/// scheduled and the names of those that were skipped, with the reason why.
+///
+/// Nodes wait for their dependencies to be applied, and fail if any of them
+/// did not. Nodes whose dependencies were skipped are skipped for the same
+/// reason.
+async fn execute_waves<'a>(
+    waves: Vec<Wave<'a>>,
+    dependencies: &HashMap<Name, HashSet<Name>>,
+    args: &Execution,
+    location: &Arc<HiveLocation>,
+    modifiers: SubCommandModifiers,
+) -> (
+    Vec<(&'a Name, Result<(), HiveLibError>)>,
+    Vec<(&'a Name, SkipReason)>,
+) {
+    let failures = &AtomicUsize::new(0);
+    let canary_failed = &AtomicBool::new(false);
+    let cancellation = &CancellationToken::new();
+    let rollout = &args.rollout;
+    let total = waves.len();
+    let mut results = Vec::new();
+    let mut skipped = Vec::new();
+
+    let skip_reason = || SkipReason::of(rollout, cancellation, failures, canary_failed);
+
+    let outcomes = &waves
+        .iter()
+        .flatten()
+        .map(|(name, _)| ((*name).clone(), watch::channel(None::<Outcome>).0))
+        .collect::<HashMap<_, _>>();
+
+    for (index, wave) in waves.into_iter().enumerate() {
+        if let Some(reason) = skip_reason() {
+            for (name, _) in wave {
+                outcomes[name].send_replace(Some(Outcome::Skipped(reason)));
+                skipped.push((name, reason));
+            }
+            continue;
+        }
+
+        if index > 0
+            && let Some(pause) = rollout.pause
+        {
+            info!("Pausing for {}s before the next wave", pause.as_secs());
+            tokio::time::sleep(pause).await;
+        }
+
+        if total > 1 {
+            info!(
+                "Applying wave {}/{total} of {} node(s)",
+                index + 1,
+                wave.len()
+            );
+        }
+
+        // later waves are only applied once every canary applied
+        let canary = index == 0 && rollout.is_canary_wave(&wave);
+        let failed_before = failures.load(Ordering::SeqCst);
+
+        let futures = futures::stream::iter(wave.into_iter().map(|(name, node)| {
             info!("Resolved {:?} to include {}", args.on, name);
 
             let should_apply_locally = should_apply_locally(node.allow_local_deployment, &name.0);
@@ -101,27 +441,127 @@ pub async fn apply(
             let context = Context {
                 node,
                 name,
-                goal: args.goal.clone().try_into().unwrap(),
-                state: StepState::default(),
+                goal: args.goal,
+                state: args.state(name),
                 no_keys: args.no_keys,
+                force_keys: args.force_keys,
+                no_prune_keys: args.no_prune_keys,
                 hive_location: location.clone(),
                 modifiers,
                 reboot: args.reboot,
                 should_apply_locally,
+                diff: args.diff,
             };
 
-            GoalExecutor::new(context)
-                .execute()
-                .map(move |result| (name, result))
-        })
-        .peekable();
+            let dependencies = dependencies[name]
+                .iter()
+                .filter(|dependency| outcomes.contains_key(dependency));
+
+            async move {
+                // checked once the node is actually scheduled
+                if let Some(reason) = skip_reason() {
+                    outcomes[name].send_replace(Some(Outcome::Skipped(reason)));
+                    return (name, Either::Left(reason));
+                }
+
+                if let Some(unapplied) =
+                    await_dependencies(name, dependencies, outcomes, skip_reason).await
+                {
+                    outcomes[name].send_replace(Some(match &unapplied {
+                        Either::Left(reason) => Outcome::Skipped(*reason),
+                        Either::Right(error) => node_failed(args, name, error),
+                    }));
+
+                    return (name, unapplied.map_right(Err));
+                }
+
+                let result = args.executor(context, cancellation).execute().await;
+
+                if result.is_err() && !cancellation.is_cancelled() {
+                    failures.fetch_add(1, Ordering::SeqCst);
+
+                    if args.fail_fast {
+                        warn!("Cancelling all nodes as {name} failed");
+                        cancellation.cancel();
+                    }
+                }
+
+                outcomes[name].send_replace(Some(match &result {
+                    Ok(()) => Outcome::Applied,
+                    Err(error) => node_failed(args, name, error),
+                }));
+
+                (name, Either::Right(result))
+            }
+        }))
+        .buffer_unordered(args.parallel);
 
-    if set.peek().is_none() {
+        for (name, result) in futures.collect::<Vec<_>>().await {
+            match result {
+                Either::Left(reason) => skipped.push((name, reason)),
+                Either::Right(result) => results.push((name, result)),
+            }
+        }
+
+        if canary && failures.load(Ordering::SeqCst) > failed_before {
+            canary_failed.store(true, Ordering::SeqCst);
+        }
+    }
+
+    (results, skipped)
+}
+
+/// Reports a failed node as soon as it failed, rather than with the summary
+fn node_failed(args: &Execution, name: &Name, error: &HiveLibError) -> Outcome {
+    if matches!(args.output, OutputFormat::Json) {
+        emit(&Event::NodeFailed {
+            node: &name.0,
+            code: error.code().map(|code| code.to_string()),
+            message: error.to_string(),
+        });
+    }
+
+    Outcome::Failed
+}
+
+async fn execute(
+    hive: &mut Hive,
+    location: HiveLocation,
+    args: Execution,
+    mut modifiers: SubCommandModifiers,
+) -> Result<()> {
+    let header_span = Span::current();
+    let location = Arc::new(location);
+
+    let header_span_enter = header_span.enter();
+
+    let selection = Selection::new(&args.on, &mut modifiers);
+    let dependencies = hive.dependencies()?;
+
+    let (finished, nodes): (Vec<_>, Vec<_>) = hive
+        .nodes
+        .iter_mut()
+        .filter(|(name, node)| selection.contains(name, node))
+        .partition(|(name, _)| {
+            args.journal
+                .as_ref()
+                .is_some_and(|journal| journal.finished(name))
+        });
+
+    if !finished.is_empty() {
+        info!(
+            "Skipping {} node(s) finished by the previous apply: {:?}",
+            finished.len(),
+            finished.iter().map(|(name, _)| name).collect::<Vec<_>>()
+        );
+    }
+
+    if nodes.is_empty() && finished.is_empty() {
         error!("There are no nodes selected for deployment");
     }
 
-    let futures = futures::stream::iter(set).buffer_unordered(args.parallel);
-    let result = futures.collect::<Vec<_>>().await;
+    let waves = args.rollout.waves(nodes, &dependencies);
+    let (result, skipped) = execute_waves(waves, &dependencies, &args, &location, modifiers).await;
     let (successful, errors): (Vec<_>, Vec<_>) =
         result
             .into_iter()
@@ -138,6 +578,38 @@ pub async fn apply(
         );
     }
 
+    for reason in [
+        SkipReason::Cancelled,
+        SkipReason::CanaryFailed,
+        SkipReason::TooManyFailures,
+    ] {
+        let names = skipped
+            .iter()
+            .filter(|(_, skipped)| *skipped == reason)
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+
+        if !names.is_empty() {
+            warn!("Skipped {} node(s) {reason}: {names:?}", names.len());
+        }
+    }
+
+    if matches!(args.output, OutputFormat::Json) {
+        emit(&Event::Summary {
+            succeeded: successful.iter().map(|name| &*name.0).collect(),
+            failed: errors.iter().map(|(name, _)| &*name.0).collect(),
+            skipped: skipped.iter().map(|(name, _)| &*name.0).collect(),
+        });
+    }
+
+    if let Some(journal) = &args.journal {
+        if errors.is_empty() && skipped.is_empty() {
+            journal.remove()?;
+        } else {
+            info!("Pass `--resume` to skip the nodes that were applied");
+        }
+    }
+
     std::mem::drop(header_span_enter);
     std::mem::drop(header_span);
 
@@ -153,3 +625,63 @@ pub async fn apply(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn selection(on: &[&str]) -> Selection {
+        let on = on
+            .iter()
+            .map(|target| ApplyTarget::from((*target).to_string()))
+            .collect::<Vec<_>>();
+
+        Selection::new(&on, &mut SubCommandModifiers::default())
+    }
+
+    fn selected(selection: &Selection) -> Vec<&'static str> {
+        let nodes: [(&str, &[&str]); 4] = [
+            ("web-1", &["prod", "web"]),
+            ("web-2", &["staging", "web"]),
+            ("db-1", &["prod", "db"]),
+            ("cache", &[]),
+        ];
+
+        nodes
+            .into_iter()
+            .filter(|(name, tags)| {
+                selection.contains_tagged(&Name((*name).into()), &|tag| tags.contains(&tag))
+            })
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    #[test]
+    fn select_nodes() {
+        assert_eq!(
+            selected(&selection(&[])),
+            ["web-1", "web-2", "db-1", "cache"]
+        );
+        assert_eq!(
+            selected(&selection(&["web-*", "cache"])),
+            ["web-1", "web-2", "cache"]
+        );
+        assert_eq!(selected(&selection(&["@prod"])), ["web-1", "db-1"]);
+        assert_eq!(selected(&selection(&["@prod&web-*"])), ["web-1"]);
+        assert_eq!(
+            selected(&selection(&["@prod&!@db", "@staging"])),
+            ["web-1", "web-2"]
+        );
+        assert!(selected(&selection(&["no-such-node"])).is_empty());
+    }
+
+    #[test]
+    fn select_only_excluded() {
+        // negations alone exclude from every node
+        assert_eq!(selected(&selection(&["!@prod"])), ["web-2", "cache"]);
+        assert_eq!(selected(&selection(&["!@web", "!cache"])), ["db-1"]);
+
+        // otherwise they exclude from the nodes the other targets select
+        assert_eq!(selected(&selection(&["@web", "!web-2"])), ["web-1"]);
+    }
+}
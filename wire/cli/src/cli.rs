@@ -6,13 +6,17 @@ use clap::{Args, Parser, Subcommand, ValueEnum};
 use clap_complete::Shell;
 use clap_num::number_range;
 use clap_verbosity_flag::InfoLevel;
+use itertools::Itertools;
 use lib::SubCommandModifiers;
 use lib::hive::Hive;
 use lib::hive::node::{Goal as HiveGoal, Name, SwitchToConfigurationGoal};
+use lib::hive::policy::StepKind;
 
 use std::io::IsTerminal;
+use std::path::PathBuf;
 use std::{
     fmt::{self, Display, Formatter},
+    str::FromStr,
     sync::Arc,
 };
 
@@ -61,6 +65,12 @@ pub struct Cli {
 pub enum ApplyTarget {
     Node(Name),
     Tag(String),
+    /// A node name containing `*` or `?`
+    Glob(String),
+    /// Selects the nodes the inner target does not
+    Not(Box<ApplyTarget>),
+    /// `&` separated targets, which must all select a node
+    All(Vec<ApplyTarget>),
     Stdin,
 }
 
@@ -70,8 +80,23 @@ impl From<String> for ApplyTarget {
             return ApplyTarget::Stdin;
         }
 
+        if value.contains('&') {
+            return ApplyTarget::All(
+                value
+                    .split('&')
+                    .map(|part| ApplyTarget::from(part.to_string()))
+                    .collect(),
+            );
+        }
+
+        if let Some(stripped) = value.strip_prefix('!') {
+            return ApplyTarget::Not(Box::new(ApplyTarget::from(stripped.to_string())));
+        }
+
         if let Some(stripped) = value.strip_prefix("@") {
             ApplyTarget::Tag(stripped.to_string())
+        } else if value.contains(['*', '?']) {
+            ApplyTarget::Glob(value)
         } else {
             ApplyTarget::Node(Name(Arc::from(value.as_str())))
         }
@@ -83,26 +108,127 @@ impl Display for ApplyTarget {
         match self {
             ApplyTarget::Node(name) => name.fmt(f),
             ApplyTarget::Tag(tag) => write!(f, "@{tag}"),
+            ApplyTarget::Glob(pattern) => write!(f, "{pattern}"),
+            ApplyTarget::Not(target) => write!(f, "!{target}"),
+            ApplyTarget::All(targets) => {
+                write!(f, "{}", targets.iter().map(ToString::to_string).join("&"))
+            }
             ApplyTarget::Stdin => write!(f, "#stdin"),
         }
     }
 }
 
+/// Matches `name` against `pattern`, where `*` matches any number of
+/// characters and `?` matches exactly one.
+fn glob_matches(pattern: &[char], name: &[char]) -> bool {
+    match (pattern.split_first(), name.split_first()) {
+        (None, None) => true,
+        (Some(('*', rest)), _) => {
+            glob_matches(rest, name) || (!name.is_empty() && glob_matches(pattern, &name[1..]))
+        }
+        (Some(('?', rest)), Some((_, name))) => glob_matches(rest, name),
+        (Some((expected, rest)), Some((actual, name))) if expected == actual => {
+            glob_matches(rest, name)
+        }
+        _ => false,
+    }
+}
+
+impl ApplyTarget {
+    /// Whether the target selects the node named `name`, which has the tags
+    /// `has_tag` accepts. `-` never selects anything by itself.
+    pub fn matches_tagged(&self, name: &Name, has_tag: &dyn Fn(&str) -> bool) -> bool {
+        match self {
+            ApplyTarget::Node(target) => target == name,
+            ApplyTarget::Tag(tag) => has_tag(tag),
+            ApplyTarget::Glob(pattern) => glob_matches(
+                &pattern.chars().collect::<Vec<_>>(),
+                &name.0.chars().collect::<Vec<_>>(),
+            ),
+            ApplyTarget::Not(target) => !target.matches_tagged(name, has_tag),
+            ApplyTarget::All(targets) => targets
+                .iter()
+                .all(|target| target.matches_tagged(name, has_tag)),
+            ApplyTarget::Stdin => false,
+        }
+    }
+}
+
 fn more_than_zero(s: &str) -> Result<usize, String> {
     number_range(s, 1, usize::MAX)
 }
 
-#[derive(Args)]
-pub struct ApplyArgs {
-    #[arg(value_enum, default_value_t)]
-    pub goal: Goal,
+#[derive(Clone, Copy, Debug)]
+pub enum BatchSize {
+    Count(usize),
+    Percent(usize),
+}
+
+impl FromStr for BatchSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_suffix('%') {
+            Some(percent) => number_range(percent, 1, 100).map(BatchSize::Percent),
+            None => more_than_zero(s).map(BatchSize::Count),
+        }
+    }
+}
+
+/// Parses `KIND=VALUE`
+fn step_kind_value<T: FromStr>(s: &str) -> Result<(StepKind, T), String>
+where
+    T::Err: Display,
+{
+    let (kind, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected KIND=VALUE, found `{s}`"))?;
+
+    Ok((
+        kind.parse()?,
+        value.parse().map_err(|err: T::Err| err.to_string())?,
+    ))
+}
+
+fn step_timeout(s: &str) -> Result<(StepKind, u64), String> {
+    match step_kind_value(s)? {
+        (_, 0) => Err("timeouts must be more than zero seconds".to_string()),
+        timeout => Ok(timeout),
+    }
+}
+
+fn step_retries(s: &str) -> Result<(StepKind, u32), String> {
+    match step_kind_value(s)? {
+        (kind, _) if !kind.is_idempotent() => Err(format!(
+            "{kind} steps are not retried, only ping, keys, and push"
+        )),
+        retries => Ok(retries),
+    }
+}
 
-    /// List of literal node names, a literal `-`, or `@` prefixed tags.
+/// The nodes a command acts on
+#[derive(Args)]
+pub struct TargetArgs {
+    /// List of node names or globs, a literal `-`, or `@` prefixed tags.
+    ///
+    /// Prefix a target with `!` to exclude the nodes it selects, and join
+    /// targets with `&` to select only the nodes matching all of them, such as
+    /// `@prod&!@db`.
     ///
     /// `-` will read additional values from stdin, seperated by whitespace.
     /// Any `-` implies `--non-interactive`.
     #[arg(short, long, value_name = "NODE | @TAG | `-`", num_args = 1..)]
     pub on: Vec<ApplyTarget>,
+}
+
+#[derive(Args)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct ApplyArgs {
+    #[arg(value_enum, default_value_t)]
+    pub goal: Goal,
+
+    #[command(flatten)]
+    pub targets: TargetArgs,
 
     #[arg(short, long, default_value_t = 10, value_parser=more_than_zero)]
     pub parallel: usize,
@@ -111,6 +237,15 @@ pub struct ApplyArgs {
     #[arg(short, long, default_value_t = false)]
     pub no_keys: bool,
 
+    /// Upload every key, including keys already in place on the node
+    #[arg(long, default_value_t = false, conflicts_with = "no_keys")]
+    pub force_keys: bool,
+
+    /// Keep keys on the nodes that are no longer declared, instead of
+    /// removing them
+    #[arg(long, default_value_t = false)]
+    pub no_prune_keys: bool,
+
     /// Overrides deployment.buildOnTarget.
     #[arg(short, long, value_name = "NODE")]
     pub always_build_local: Vec<String>,
@@ -119,9 +254,209 @@ pub struct ApplyArgs {
     #[arg(short, long, default_value_t = false)]
     pub reboot: bool,
 
+    /// Show added, removed, and changed packages against each node's current
+    /// system before activation
+    #[arg(long, default_value_t = false)]
+    pub diff: bool,
+
+    /// Ask before activating each node. Implies `--diff`, and cannot be
+    /// combined with `--non-interactive`
+    #[arg(long, default_value_t = false, conflicts_with = "non_interactive")]
+    pub confirm: bool,
+
+    /// Nodes to apply before all others, in a wave of their own.
+    ///
+    /// Accepts the same values as `--on`.
+    #[arg(long, value_name = "NODE | @TAG | `-`", num_args = 1..)]
+    pub canary: Vec<ApplyTarget>,
+
+    /// Apply nodes in waves of N nodes, or P% of the selected nodes
+    #[arg(long, value_name = "N | P%")]
+    pub batch_size: Option<BatchSize>,
+
+    /// Seconds to wait between waves
+    #[arg(long, value_name = "SECONDS")]
+    pub batch_pause: Option<u64>,
+
+    /// Cancel every running node, and schedule no more, once any node fails
+    ///
+    /// Cancelled nodes are cleaned up before wire exits.
+    #[arg(long, default_value_t = false, conflicts_with = "keep_going")]
+    pub fail_fast: bool,
+
+    /// Apply every node even after a node failed. This is the default
+    #[arg(long, default_value_t = false)]
+    pub keep_going: bool,
+
+    /// Stop scheduling nodes once more than N nodes failed
+    #[arg(long, value_name = "N")]
+    pub max_failures: Option<usize>,
+
+    /// Fail steps of a kind that take longer than the given seconds.
+    /// Overrides deployment.timeouts.
+    ///
+    /// KIND is one of `ping`, `keys`, `push`, `build`, or `activate`. Can be
+    /// passed multiple times.
+    #[arg(long, value_name = "KIND=SECONDS", value_parser = step_timeout)]
+    pub timeout: Vec<(StepKind, u64)>,
+
+    /// Retry failed steps of a kind up to the given number of times.
+    /// Overrides deployment.retries.
+    ///
+    /// KIND is one of `ping`, `keys`, or `push`. Can be passed multiple times.
+    #[arg(long, value_name = "KIND=COUNT", value_parser = step_retries)]
+    pub retries: Vec<(StepKind, u32)>,
+
+    /// Apply a plan written by `wire plan`, without evaluating the nodes
+    /// again.
+    ///
+    /// Fails if the hive or any planned key changed since the plan was made.
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["goal", "on", "no_keys"])]
+    pub plan: Option<PathBuf>,
+
+    /// Skip nodes that a previous, interrupted apply of the same goal to this
+    /// hive finished, and reuse what it already built
+    #[arg(long, default_value_t = false)]
+    pub resume: bool,
+
+    /// Format of the output on stdout
+    ///
+    /// `json` prints versioned, newline-delimited events describing the
+    /// progress of each node.
+    #[arg(long, value_enum, default_value_t)]
+    pub output: OutputFormat,
+
     /// Unconditionally accept SSH host keys [!!]
     ///
-    /// Sets `StrictHostKeyChecking` to `no`.
+    /// Sets `StrictHostKeyChecking` to `no`, except for nodes with pinned
+    /// `hostKeys`.
+    /// Vulnerable to man-in-the-middle attacks, use with caution.
+    #[arg(long, default_value_t = false)]
+    pub ssh_accept_host: bool,
+}
+
+#[derive(Args)]
+pub struct PlanArgs {
+    #[arg(value_enum, default_value_t)]
+    pub goal: Goal,
+
+    #[command(flatten)]
+    pub targets: TargetArgs,
+
+    #[arg(short, long, default_value_t = 10, value_parser=more_than_zero)]
+    pub parallel: usize,
+
+    /// Skip key uploads. noop when [GOAL] = Keys
+    #[arg(short, long, default_value_t = false)]
+    pub no_keys: bool,
+
+    /// Overrides deployment.buildOnTarget.
+    #[arg(short, long, value_name = "NODE")]
+    pub always_build_local: Vec<String>,
+
+    /// File to write the plan to
+    #[arg(short, long, value_name = "FILE", default_value = "plan.json")]
+    pub output: PathBuf,
+
+    /// Unconditionally accept SSH host keys [!!]
+    ///
+    /// Sets `StrictHostKeyChecking` to `no`, except for nodes with pinned
+    /// `hostKeys`.
+    /// Vulnerable to man-in-the-middle attacks, use with caution.
+    #[arg(long, default_value_t = false)]
+    pub ssh_accept_host: bool,
+}
+
+#[derive(Args)]
+pub struct RollbackArgs {
+    /// Generation of the system profile to switch to.
+    ///
+    /// Defaults to the generation before the current one.
+    #[arg(short, long)]
+    pub generation: Option<u32>,
+
+    #[command(flatten)]
+    pub targets: TargetArgs,
+
+    #[arg(short, long, default_value_t = 10, value_parser=more_than_zero)]
+    pub parallel: usize,
+
+    /// Unconditionally accept SSH host keys [!!]
+    ///
+    /// Sets `StrictHostKeyChecking` to `no`, except for nodes with pinned
+    /// `hostKeys`.
+    /// Vulnerable to man-in-the-middle attacks, use with caution.
+    #[arg(long, default_value_t = false)]
+    pub ssh_accept_host: bool,
+}
+
+#[derive(Args)]
+pub struct ExecArgs {
+    /// Command to run on each node, after `--`
+    ///
+    /// Each word is passed to the node as is. Run `sh -c '...'` for pipes,
+    /// globs, or variables.
+    #[arg(required = true, num_args = 1.., last = true)]
+    pub command: Vec<String>,
+
+    #[command(flatten)]
+    pub targets: TargetArgs,
+
+    #[arg(short, long, default_value_t = 10, value_parser=more_than_zero)]
+    pub parallel: usize,
+
+    /// Run the command as root
+    #[arg(short, long, default_value_t = false)]
+    pub elevated: bool,
+
+    /// Return in JSON format
+    #[arg(short, long, default_value_t = false)]
+    pub json: bool,
+
+    /// Unconditionally accept SSH host keys [!!]
+    ///
+    /// Sets `StrictHostKeyChecking` to `no`, except for nodes with pinned
+    /// `hostKeys`.
+    /// Vulnerable to man-in-the-middle attacks, use with caution.
+    #[arg(long, default_value_t = false)]
+    pub ssh_accept_host: bool,
+}
+
+#[derive(Args)]
+pub struct SshArgs {
+    /// Node to log into
+    #[arg(value_name = "NODE")]
+    pub node: String,
+
+    /// Command to run instead of a login shell, after `--`
+    #[arg(num_args = 1.., last = true)]
+    pub command: Vec<String>,
+
+    /// Unconditionally accept SSH host keys [!!]
+    ///
+    /// Sets `StrictHostKeyChecking` to `no`, except for nodes with pinned
+    /// `hostKeys`.
+    /// Vulnerable to man-in-the-middle attacks, use with caution.
+    #[arg(long, default_value_t = false)]
+    pub ssh_accept_host: bool,
+}
+
+#[derive(Args)]
+pub struct StatusArgs {
+    #[command(flatten)]
+    pub targets: TargetArgs,
+
+    #[arg(short, long, default_value_t = 10, value_parser=more_than_zero)]
+    pub parallel: usize,
+
+    /// Return in JSON format
+    #[arg(short, long, default_value_t = false)]
+    pub json: bool,
+
+    /// Unconditionally accept SSH host keys [!!]
+    ///
+    /// Sets `StrictHostKeyChecking` to `no`, except for nodes with pinned
+    /// `hostKeys`.
     /// Vulnerable to man-in-the-middle attacks, use with caution.
     #[arg(long, default_value_t = false)]
     pub ssh_accept_host: bool,
@@ -131,13 +466,38 @@ pub struct ApplyArgs {
 pub enum Commands {
     /// Deploy nodes
     Apply(ApplyArgs),
+    /// Evaluate and build nodes, and write what applying them would do to a
+    /// plan file for `wire apply --plan`
+    Plan(PlanArgs),
+    /// Activate a previous system generation on nodes
+    Rollback(RollbackArgs),
+    /// Check whether nodes are running the system the hive evaluates to
+    ///
+    /// Exits with an error if any node drifted or could not be checked.
+    #[clap(visible_alias = "drift")]
+    Status(StatusArgs),
+    /// Run a shell command on nodes
+    ///
+    /// Exits with an error if the command failed on any node.
+    Exec(ExecArgs),
+    /// Open a shell on a node, using the hosts, user, and port of the hive
+    ///
+    /// Exits with the exit code of the shell or command.
+    Ssh(SshArgs),
     /// Inspect hive
-    #[clap(visible_alias = "show")]
+    ///
+    /// With `--on`, only prints the names of the nodes the targets select.
+    // `-o` is taken by `--online`
+    #[clap(visible_alias = "show", mut_arg("on", |arg| arg.short(None)))]
     Inspect {
-        /// Include liveliness
+        /// Ping every node and include facts such as its current system,
+        /// kernel, uptime, and whether a reboot is required
         #[arg(short, long, default_value_t = false)]
         online: bool,
 
+        #[command(flatten)]
+        targets: TargetArgs,
+
         /// Return in JSON format
         #[arg(short, long, default_value_t = false)]
         json: bool,
@@ -151,6 +511,15 @@ pub enum Commands {
     },
 }
 
+#[derive(Clone, Copy, Debug, Default, ValueEnum, Display)]
+pub enum OutputFormat {
+    /// Human readable logs on stderr only
+    #[default]
+    Human,
+    /// Newline-delimited JSON events on stdout
+    Json,
+}
+
 #[derive(Clone, Debug, Default, ValueEnum, Display)]
 pub enum Goal {
     /// Make the configuration the boot default and activate now
@@ -204,9 +573,104 @@ impl ToSubCommandModifiers for Cli {
             show_trace: self.show_trace,
             non_interactive: self.non_interactive,
             ssh_accept_host: match &self.command {
-                Commands::Apply(args) if args.ssh_accept_host => lib::StrictHostKeyChecking::No,
+                Commands::Apply(ApplyArgs {
+                    ssh_accept_host: true,
+                    ..
+                })
+                | Commands::Plan(PlanArgs {
+                    ssh_accept_host: true,
+                    ..
+                })
+                | Commands::Rollback(RollbackArgs {
+                    ssh_accept_host: true,
+                    ..
+                })
+                | Commands::Status(StatusArgs {
+                    ssh_accept_host: true,
+                    ..
+                })
+                | Commands::Exec(ExecArgs {
+                    ssh_accept_host: true,
+                    ..
+                })
+                | Commands::Ssh(SshArgs {
+                    ssh_accept_host: true,
+                    ..
+                }) => lib::StrictHostKeyChecking::No,
                 _ => lib::StrictHostKeyChecking::default(),
             },
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glob(pattern: &str, name: &str) -> bool {
+        glob_matches(
+            &pattern.chars().collect::<Vec<_>>(),
+            &name.chars().collect::<Vec<_>>(),
+        )
+    }
+
+    fn matches(target: &str, tags: &[&str]) -> bool {
+        ApplyTarget::from(target.to_string())
+            .matches_tagged(&Name("web-1".into()), &|tag| tags.contains(&tag))
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob("*", ""));
+        assert!(glob("*", "node-1"));
+        assert!(glob("node-*", "node-"));
+        assert!(glob("node-?", "node-1"));
+        assert!(!glob("node-?", "node-"));
+        assert!(!glob("node-?", "node-10"));
+        assert!(glob("*-*-db", "eu-west-db"));
+        assert!(glob("**", "a"));
+        assert!(!glob("node-*", "web-1"));
+        assert!(!glob("", "node-1"));
+    }
+
+    #[test]
+    fn parse_targets() {
+        assert!(matches!(
+            ApplyTarget::from("-".to_string()),
+            ApplyTarget::Stdin
+        ));
+        assert!(
+            matches!(ApplyTarget::from("@cloud".to_string()), ApplyTarget::Tag(tag) if tag == "cloud")
+        );
+        assert!(matches!(
+            ApplyTarget::from("web-*".to_string()),
+            ApplyTarget::Glob(_)
+        ));
+        assert!(matches!(
+            ApplyTarget::from("node-1".to_string()),
+            ApplyTarget::Node(_)
+        ));
+        assert!(matches!(
+            ApplyTarget::from("!@db".to_string()),
+            ApplyTarget::Not(target) if matches!(*target, ApplyTarget::Tag(_))
+        ));
+
+        let target = ApplyTarget::from("@prod&!@db&web-*".to_string());
+        assert!(matches!(&target, ApplyTarget::All(targets) if targets.len() == 3));
+        assert_eq!(target.to_string(), "@prod&!@db&web-*");
+    }
+
+    #[test]
+    fn match_targets() {
+        let web = ["prod", "cloud"];
+
+        assert!(matches("@prod", &web));
+        assert!(matches("web-?", &web));
+        assert!(matches("@prod&web-*", &web));
+        assert!(!matches("@prod&db-*", &web));
+        assert!(!matches("@prod&!@cloud", &web));
+        assert!(matches("!@db", &web));
+        assert!(!matches("!web-1", &web));
+        assert!(!matches("-", &web));
+    }
+}
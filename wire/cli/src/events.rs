@@ -0,0 +1,288 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+// Copyright 2024-2025 wire Contributors
+
+use std::io::Write;
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+use tracing::{
+    Subscriber,
+    field::{Field, Visit},
+};
+use tracing_subscriber::{Layer, layer::Context, registry::LookupSpan};
+
+/// Bumped whenever a field is removed or changes meaning. New fields and
+/// events may be added without a bump.
+pub const EVENT_SCHEMA_VERSION: u32 = 1;
+
+/// Events printed to stdout, one per line, by `--output json`
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    NodeStarted {
+        node: &'a str,
+    },
+    StepStarted {
+        node: &'a str,
+        step: &'a str,
+        progress: &'a str,
+    },
+    StepFinished {
+        node: &'a str,
+        step: &'a str,
+        duration_ms: u64,
+    },
+    Log {
+        node: Option<&'a str>,
+        level: &'a str,
+        message: &'a str,
+        fields: &'a Map<String, Value>,
+    },
+    NodeFailed {
+        node: &'a str,
+        code: Option<String>,
+        message: String,
+    },
+    Summary {
+        succeeded: Vec<&'a str>,
+        failed: Vec<&'a str>,
+        /// Nodes never applied, as `--fail-fast`, a canary or `--max-failures`
+        /// stopped the apply
+        skipped: Vec<&'a str>,
+    },
+}
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    version: u32,
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+}
+
+pub fn emit(event: &Event) {
+    let mut stdout = std::io::stdout().lock();
+    let envelope = Envelope {
+        version: EVENT_SCHEMA_VERSION,
+        event,
+    };
+
+    if serde_json::to_writer(&mut stdout, &envelope).is_ok() {
+        let _ = writeln!(stdout);
+        let _ = stdout.flush();
+    }
+}
+
+/// The node an `execute` span was created for
+struct NodeName(String);
+
+#[derive(Default)]
+struct JsonVisitor {
+    message: Option<String>,
+    fields: Map<String, Value>,
+}
+
+impl Visit for JsonVisitor {
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.fields.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.fields.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.fields.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = Some(value.to_string());
+        } else {
+            self.fields.insert(field.name().to_string(), value.into());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.record_str(field, &format!("{value:?}"));
+    }
+}
+
+impl JsonVisitor {
+    fn str(&self, name: &str) -> Option<&str> {
+        self.fields.get(name).and_then(Value::as_str)
+    }
+}
+
+/// Translates the events of `GoalExecutor::execute` into `Event`s
+pub struct EventLayer;
+
+impl<S> Layer<S> for EventLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(
+        &self,
+        attrs: &tracing::span::Attributes<'_>,
+        id: &tracing::span::Id,
+        ctx: Context<'_, S>,
+    ) {
+        if attrs.metadata().name() != "execute" {
+            return;
+        }
+
+        let mut visitor = JsonVisitor::default();
+        attrs.record(&mut visitor);
+
+        let Some(node) = visitor.str("node") else {
+            return;
+        };
+
+        emit(&Event::NodeStarted { node });
+
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(NodeName(node.to_string()));
+        }
+    }
+
+    fn on_event(&self, event: &tracing::Event<'_>, ctx: Context<'_, S>) {
+        let mut visitor = JsonVisitor::default();
+        event.record(&mut visitor);
+
+        let node = ctx.event_scope(event).and_then(|scope| {
+            scope
+                .from_root()
+                .find_map(|span| span.extensions().get::<NodeName>().map(|x| x.0.clone()))
+        });
+
+        match (
+            node.as_deref(),
+            visitor.str("step"),
+            visitor.str("progress"),
+            visitor.fields.get("elapsed_ms").and_then(Value::as_u64),
+        ) {
+            (Some(node), Some(step), Some(progress), _) => {
+                emit(&Event::StepStarted {
+                    node,
+                    step,
+                    progress,
+                });
+            }
+            (Some(node), Some(step), _, Some(duration_ms)) => {
+                emit(&Event::StepFinished {
+                    node,
+                    step,
+                    duration_ms,
+                });
+            }
+            _ if *event.metadata().level() <= tracing::Level::INFO => {
+                emit(&Event::Log {
+                    node: node.as_deref(),
+                    level: event.metadata().level().as_str(),
+                    message: visitor.message.as_deref().unwrap_or_default(),
+                    fields: &visitor.fields,
+                });
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn serialize(event: &Event) -> Value {
+        serde_json::to_value(Envelope {
+            version: EVENT_SCHEMA_VERSION,
+            event,
+        })
+        .unwrap()
+    }
+
+    /// Changing any of these needs an `EVENT_SCHEMA_VERSION` bump, unless
+    /// fields or events were only added
+    #[test]
+    fn schema() {
+        let fields = Map::from_iter([("attempt".to_string(), json!(2))]);
+
+        assert_eq!(EVENT_SCHEMA_VERSION, 1);
+        assert_eq!(
+            serialize(&Event::NodeStarted { node: "web" }),
+            json!({ "version": 1, "event": "node_started", "node": "web" })
+        );
+        assert_eq!(
+            serialize(&Event::StepStarted {
+                node: "web",
+                step: "Build the node",
+                progress: "3/9",
+            }),
+            json!({
+                "version": 1,
+                "event": "step_started",
+                "node": "web",
+                "step": "Build the node",
+                "progress": "3/9",
+            })
+        );
+        assert_eq!(
+            serialize(&Event::StepFinished {
+                node: "web",
+                step: "Build the node",
+                duration_ms: 1500,
+            }),
+            json!({
+                "version": 1,
+                "event": "step_finished",
+                "node": "web",
+                "step": "Build the node",
+                "duration_ms": 1500,
+            })
+        );
+        assert_eq!(
+            serialize(&Event::Log {
+                node: None,
+                level: "INFO",
+                message: "Retrying",
+                fields: &fields,
+            }),
+            json!({
+                "version": 1,
+                "event": "log",
+                "node": null,
+                "level": "INFO",
+                "message": "Retrying",
+                "fields": { "attempt": 2 },
+            })
+        );
+        assert_eq!(
+            serialize(&Event::NodeFailed {
+                node: "web",
+                code: Some("wire::activation::SwitchToConfiguration".to_string()),
+                message: "failed to switch".to_string(),
+            }),
+            json!({
+                "version": 1,
+                "event": "node_failed",
+                "node": "web",
+                "code": "wire::activation::SwitchToConfiguration",
+                "message": "failed to switch",
+            })
+        );
+        assert_eq!(
+            serialize(&Event::Summary {
+                succeeded: vec!["web"],
+                failed: vec!["db"],
+                skipped: vec![],
+            }),
+            json!({
+                "version": 1,
+                "event": "summary",
+                "succeeded": ["web"],
+                "failed": ["db"],
+                "skipped": [],
+            })
+        );
+    }
+}
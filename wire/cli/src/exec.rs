@@ -0,0 +1,94 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+// Copyright 2024-2025 wire Contributors
+
+use std::collections::BTreeMap;
+
+use futures::StreamExt;
+use lib::SubCommandModifiers;
+use lib::hive::Hive;
+use lib::hive::exec::{ExecResult, exec as exec_on_node};
+use miette::{Diagnostic, IntoDiagnostic, Result};
+use thiserror::Error;
+use tracing::error;
+
+use crate::apply::Selection;
+use crate::cli::ExecArgs;
+
+#[derive(Debug, Error, Diagnostic)]
+#[error(
+    "the command exited with a non-zero code on {failed} node(s), and could not be ran on {unreachable} node(s)."
+)]
+struct ExecFailed {
+    failed: usize,
+    unreachable: usize,
+}
+
+pub async fn exec(
+    hive: &mut Hive,
+    args: ExecArgs,
+    mut modifiers: SubCommandModifiers,
+) -> Result<()> {
+    let selection = Selection::new(&args.targets.on, &mut modifiers);
+    let command = &args.command;
+
+    let mut set = hive
+        .nodes
+        .iter_mut()
+        .filter(|(name, node)| selection.contains(name, node))
+        .map(|(name, node)| async move {
+            (
+                name.0.to_string(),
+                exec_on_node(name, node, command, args.elevated, modifiers).await,
+            )
+        })
+        .peekable();
+
+    if set.peek().is_none() {
+        error!("There are no nodes selected");
+    }
+
+    let results = futures::stream::iter(set)
+        .buffer_unordered(args.parallel)
+        .collect::<BTreeMap<_, _>>()
+        .await;
+
+    if args.json {
+        println!("{}", serde_json::to_string(&results).into_diagnostic()?);
+    } else {
+        for (name, result) in &results {
+            if let ExecResult::Exited { output, .. } = result
+                && !output.is_empty()
+            {
+                println!("=== {name} ===\n{output}\n");
+            }
+        }
+
+        for (name, result) in &results {
+            match result {
+                ExecResult::Exited { host, .. } if **host != **name => {
+                    println!("{name}: {result} on {host}");
+                }
+                _ => println!("{name}: {result}"),
+            }
+        }
+    }
+
+    let failed = results
+        .values()
+        .filter(|result| matches!(result, ExecResult::Exited { code, .. } if *code != 0))
+        .count();
+    let unreachable = results
+        .values()
+        .filter(|result| matches!(result, ExecResult::Failed { .. }))
+        .count();
+
+    if failed + unreachable > 0 {
+        return Err(ExecFailed {
+            failed,
+            unreachable,
+        }
+        .into());
+    }
+
+    Ok(())
+}
@@ -3,12 +3,14 @@
 
 use std::process::Command;
 
+use crate::apply::Selection;
 use crate::cli::Cli;
 use crate::cli::ToSubCommandModifiers;
 use crate::tracing_setup::setup_logging;
 use clap::CommandFactory;
 use clap::Parser;
 use clap_complete::generate;
+use itertools::Itertools;
 use lib::hive::Hive;
 use lib::hive::get_hive_location;
 use miette::IntoDiagnostic;
@@ -21,6 +23,12 @@ extern crate enum_display_derive;
 
 mod apply;
 mod cli;
+mod events;
+mod exec;
+mod plan;
+mod rollout;
+mod ssh;
+mod status;
 mod tracing_setup;
 
 #[cfg(feature = "dhat-heap")]
@@ -35,7 +43,16 @@ async fn main() -> Result<()> {
     let args = Cli::parse();
 
     let modifiers = args.to_subcommand_modifiers();
-    setup_logging(&args.verbose);
+    setup_logging(
+        &args.verbose,
+        matches!(
+            args.command,
+            cli::Commands::Apply(cli::ApplyArgs {
+                output: cli::OutputFormat::Json,
+                ..
+            })
+        ),
+    );
 
     #[cfg(debug_assertions)]
     if args.markdown_help {
@@ -54,9 +71,44 @@ async fn main() -> Result<()> {
             let mut hive = Hive::new_from_path(&location, modifiers).await?;
             apply::apply(&mut hive, location, apply_args, modifiers).await?;
         }
-        cli::Commands::Inspect { online: _, json } => println!("{}", {
-            let hive = Hive::new_from_path(&location, modifiers).await?;
-            if json {
+        cli::Commands::Plan(plan_args) => {
+            let mut hive = Hive::new_from_path(&location, modifiers).await?;
+            plan::plan(&mut hive, location, plan_args, modifiers).await?;
+        }
+        cli::Commands::Rollback(rollback_args) => {
+            let mut hive = Hive::new_from_path(&location, modifiers).await?;
+            apply::rollback(&mut hive, location, rollback_args, modifiers).await?;
+        }
+        cli::Commands::Status(status_args) => {
+            let mut hive = Hive::new_from_path(&location, modifiers).await?;
+            status::status(&mut hive, location, status_args, modifiers).await?;
+        }
+        cli::Commands::Exec(exec_args) => {
+            let mut hive = Hive::new_from_path(&location, modifiers).await?;
+            exec::exec(&mut hive, exec_args, modifiers).await?;
+        }
+        cli::Commands::Ssh(ssh_args) => {
+            let mut hive = Hive::new_from_path(&location, modifiers).await?;
+            ssh::ssh(&mut hive, ssh_args, modifiers).await?;
+        }
+        cli::Commands::Inspect {
+            online,
+            json,
+            targets,
+        } => println!("{}", {
+            let mut hive = Hive::new_from_path(&location, modifiers).await?;
+            if online {
+                hive.gather_facts(modifiers).await;
+            }
+            if !targets.on.is_empty() {
+                let selected = Selection::new(&targets.on, &mut { modifiers }).resolve(&hive);
+
+                if json {
+                    serde_json::to_string(&selected).into_diagnostic()?
+                } else {
+                    selected.iter().join("\n")
+                }
+            } else if json {
                 serde_json::to_string(&hive).into_diagnostic()?
             } else {
                 warn!("use --json to output something scripting suitable");
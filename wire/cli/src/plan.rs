@@ -0,0 +1,94 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+// Copyright 2024-2025 wire Contributors
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use futures::StreamExt;
+use itertools::{Either, Itertools};
+use lib::SubCommandModifiers;
+use lib::hive::node::{Context, Goal, StepState, should_apply_locally};
+use lib::hive::plan::{PLAN_VERSION, Plan, fingerprint, plan_node};
+use lib::hive::steps::diff::DiffMode;
+use lib::hive::{Hive, HiveLocation};
+use miette::Result;
+use tracing::{error, info};
+
+use crate::apply::{NodeError, NodeErrors, Selection};
+use crate::cli::PlanArgs;
+
+pub async fn plan(
+    hive: &mut Hive,
+    location: HiveLocation,
+    args: PlanArgs,
+    mut modifiers: SubCommandModifiers,
+) -> Result<()> {
+    hive.force_always_local(args.always_build_local)?;
+
+    let goal: Goal = args.goal.try_into()?;
+    let fingerprint = fingerprint(&location, &args.output, modifiers).await?;
+    let selection = Selection::new(&args.targets.on, &mut modifiers);
+    let location = Arc::new(location);
+
+    let mut set = hive
+        .nodes
+        .iter_mut()
+        .filter(|(name, node)| selection.contains(name, node))
+        .map(|(name, node)| {
+            let should_apply_locally = should_apply_locally(node.allow_local_deployment, &name.0);
+
+            let context = Context {
+                name,
+                node,
+                hive_location: location.clone(),
+                modifiers,
+                no_keys: args.no_keys,
+                force_keys: false,
+                no_prune_keys: false,
+                state: StepState::default(),
+                goal,
+                reboot: false,
+                should_apply_locally,
+                diff: DiffMode::Disabled,
+            };
+
+            async move { (name, plan_node(context).await) }
+        })
+        .peekable();
+
+    if set.peek().is_none() {
+        error!("There are no nodes selected");
+    }
+
+    let (nodes, errors): (BTreeMap<_, _>, Vec<_>) = futures::stream::iter(set)
+        .buffer_unordered(args.parallel)
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .partition_map(|(name, result)| match result {
+            Ok(planned) => Either::Left((name.clone(), planned)),
+            Err(err) => Either::Right(NodeError(name.clone(), err)),
+        });
+
+    if !errors.is_empty() {
+        return Err(NodeErrors(errors).into());
+    }
+
+    let plan = Plan {
+        version: PLAN_VERSION,
+        fingerprint,
+        goal,
+        no_keys: args.no_keys,
+        nodes,
+    };
+
+    plan.write(&args.output)?;
+
+    info!(
+        "Wrote a plan for {} node(s) to {}",
+        plan.nodes.len(),
+        args.output.display()
+    );
+
+    Ok(())
+}
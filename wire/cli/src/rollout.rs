@@ -0,0 +1,260 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+// Copyright 2024-2025 wire Contributors
+
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use lib::hive::node::{Name, Node};
+
+use crate::apply::Selection;
+use crate::cli::BatchSize;
+
+/// Describes how selected nodes are split into waves, which are applied one
+/// after another
+#[derive(Default)]
+pub struct Rollout {
+    /// Nodes applied in a wave of their own, before any other node
+    pub canary: Option<Selection>,
+    pub batch_size: Option<BatchSize>,
+    /// Stop scheduling nodes once more than this many nodes failed
+    pub max_failures: Option<usize>,
+    /// Time to wait between waves
+    pub pause: Option<Duration>,
+}
+
+pub type Wave<'a> = Vec<(&'a Name, &'a mut Node)>;
+
+/// Number of selected nodes on the longest chain of dependencies leading to
+/// `name`, including itself
+fn depth<'a>(
+    name: &'a Name,
+    dependencies: &'a HashMap<Name, HashSet<Name>>,
+    selected: &HashSet<&Name>,
+    depths: &mut HashMap<&'a Name, usize>,
+) -> usize {
+    if let Some(depth) = depths.get(name) {
+        return *depth;
+    }
+
+    let depth = 1 + dependencies[name]
+        .iter()
+        .filter(|dependency| selected.contains(dependency))
+        .map(|dependency| depth(dependency, dependencies, selected, depths))
+        .max()
+        .unwrap_or_default();
+
+    depths.insert(name, depth);
+    depth
+}
+
+impl Rollout {
+    /// Splits nodes into waves. Nodes are ordered so that they come after
+    /// their dependencies, and are moved into later waves if a dependency
+    /// would otherwise be applied after them.
+    pub fn waves<'a>(
+        &self,
+        nodes: Wave<'a>,
+        dependencies: &HashMap<Name, HashSet<Name>>,
+    ) -> Vec<Wave<'a>> {
+        self.assign(nodes, dependencies, |name, node| {
+            self.canary
+                .as_ref()
+                .is_some_and(|canary| canary.contains(name, node))
+        })
+    }
+
+    fn assign<'a, T>(
+        &self,
+        mut nodes: Vec<(&'a Name, T)>,
+        dependencies: &HashMap<Name, HashSet<Name>>,
+        is_canary: impl Fn(&Name, &T) -> bool,
+    ) -> Vec<Vec<(&'a Name, T)>> {
+        let selected = nodes.iter().map(|(name, _)| *name).collect::<HashSet<_>>();
+        let mut depths = HashMap::new();
+
+        for name in &selected {
+            depth(name, dependencies, &selected, &mut depths);
+        }
+
+        // batches should not depend on `HashMap` ordering
+        let order = |a: &Name, b: &Name| depths[a].cmp(&depths[b]).then_with(|| a.0.cmp(&b.0));
+        nodes.sort_by(|(a, _), (b, _)| order(a, b));
+
+        let (canaries, rest): (Vec<_>, Vec<_>) = nodes
+            .into_iter()
+            .partition(|(name, node)| is_canary(name, node));
+        let offset = usize::from(!canaries.is_empty());
+
+        let size = match self.batch_size {
+            None => rest.len(),
+            Some(BatchSize::Count(count)) => count,
+            Some(BatchSize::Percent(percent)) => (rest.len() * percent).div_ceil(100),
+        }
+        .max(1);
+
+        let mut assigned = canaries
+            .into_iter()
+            .map(|node| (0, node))
+            .chain(
+                rest.into_iter()
+                    .enumerate()
+                    .map(|(index, node)| (offset + index / size, node)),
+            )
+            .collect::<Vec<_>>();
+        assigned.sort_by(|(_, (a, _)), (_, (b, _))| order(a, b));
+
+        // dependencies always come first, so a single pass is enough
+        let mut wave_of = HashMap::<&Name, usize>::new();
+        for (wave, (name, _)) in &mut assigned {
+            *wave = dependencies[*name]
+                .iter()
+                .filter_map(|dependency| wave_of.get(dependency))
+                .fold(*wave, |wave, dependency| wave.max(*dependency));
+            wave_of.insert(name, *wave);
+        }
+
+        let mut waves = Vec::<Vec<_>>::new();
+        for (wave, node) in assigned {
+            waves.resize_with(waves.len().max(wave + 1), Vec::new);
+            waves[wave].push(node);
+        }
+
+        waves.retain(|wave| !wave.is_empty());
+        waves
+    }
+
+    /// Whether `wave` holds the canaries, which are always in the first wave
+    pub fn is_canary_wave(&self, wave: &Wave) -> bool {
+        self.canary
+            .as_ref()
+            .is_some_and(|canary| wave.iter().any(|(name, node)| canary.contains(name, node)))
+    }
+
+    pub const fn budget_exceeded(&self, failures: usize) -> bool {
+        matches!(self.max_failures, Some(max) if failures > max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dependencies(edges: &[(&str, &[&str])]) -> HashMap<Name, HashSet<Name>> {
+        edges
+            .iter()
+            .map(|(name, dependencies)| {
+                (
+                    Name((*name).into()),
+                    dependencies
+                        .iter()
+                        .map(|name| Name((*name).into()))
+                        .collect(),
+                )
+            })
+            .collect()
+    }
+
+    fn waves(
+        rollout: &Rollout,
+        dependencies: &HashMap<Name, HashSet<Name>>,
+        canaries: &[&str],
+    ) -> Vec<Vec<String>> {
+        let nodes = dependencies.keys().map(|name| (name, ())).collect();
+
+        rollout
+            .assign(nodes, dependencies, |name, ()| {
+                canaries.contains(&name.0.as_ref())
+            })
+            .into_iter()
+            .map(|wave| {
+                wave.into_iter()
+                    .map(|(name, ())| name.0.to_string())
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn dependencies_come_first() {
+        let dependencies = dependencies(&[
+            ("app", &["db", "cache"]),
+            ("cache", &["db"]),
+            ("db", &[]),
+            ("web", &[]),
+        ]);
+
+        assert_eq!(
+            waves(&Rollout::default(), &dependencies, &[]),
+            [["db", "web", "cache", "app"]]
+        );
+    }
+
+    #[test]
+    fn batch_sizes() {
+        let dependencies =
+            dependencies(&[("a", &[]), ("b", &[]), ("c", &[]), ("d", &[]), ("e", &[])]);
+        let rollout = |batch_size| Rollout {
+            batch_size: Some(batch_size),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            waves(&rollout(BatchSize::Count(2)), &dependencies, &[]),
+            [vec!["a", "b"], vec!["c", "d"], vec!["e"]]
+        );
+        // rounds up
+        assert_eq!(
+            waves(&rollout(BatchSize::Percent(50)), &dependencies, &[]),
+            [vec!["a", "b", "c"], vec!["d", "e"]]
+        );
+        // never empty
+        assert_eq!(
+            waves(&rollout(BatchSize::Count(0)), &dependencies, &[]).len(),
+            5
+        );
+        assert_eq!(
+            waves(&rollout(BatchSize::Percent(0)), &dependencies, &[]).len(),
+            5
+        );
+    }
+
+    #[test]
+    fn canaries_come_first() {
+        let dependencies = dependencies(&[("a", &[]), ("b", &[]), ("c", &[]), ("d", &[])]);
+        let rollout = Rollout {
+            batch_size: Some(BatchSize::Count(2)),
+            ..Default::default()
+        };
+
+        // the batch size only applies to the nodes after the canaries
+        assert_eq!(
+            waves(&rollout, &dependencies, &["c"]),
+            [vec!["c"], vec!["a", "b"], vec!["d"]]
+        );
+        assert_eq!(
+            waves(&rollout, &dependencies, &["a", "b", "c", "d"]),
+            [["a", "b", "c", "d"]]
+        );
+    }
+
+    #[test]
+    fn dependencies_move_nodes_to_later_waves() {
+        let dependencies = dependencies(&[("a", &[]), ("b", &["z"]), ("c", &[]), ("z", &[])]);
+        let rollout = Rollout {
+            batch_size: Some(BatchSize::Count(2)),
+            ..Default::default()
+        };
+
+        // `b` would be in the first wave, before `z`
+        assert_eq!(
+            waves(&rollout, &dependencies, &[]),
+            [vec!["a", "c"], vec!["z", "b"]]
+        );
+
+        // a canary depending on a node that is not a canary is applied after it
+        assert_eq!(
+            waves(&Rollout::default(), &dependencies, &["b"]),
+            [vec!["a", "c", "z", "b"]]
+        );
+    }
+}
@@ -0,0 +1,32 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+// Copyright 2024-2025 wire Contributors
+
+use std::sync::Arc;
+
+use lib::SubCommandModifiers;
+use lib::errors::{HiveInitializationError, HiveLibError};
+use lib::hive::Hive;
+use lib::hive::exec::ssh as ssh_into_node;
+use lib::hive::node::Name;
+use miette::Result;
+
+use crate::cli::SshArgs;
+
+pub async fn ssh(hive: &mut Hive, args: SshArgs, modifiers: SubCommandModifiers) -> Result<()> {
+    let name = Name(Arc::from(args.node.as_str()));
+    let node = hive
+        .nodes
+        .get_mut(&name)
+        .ok_or(HiveLibError::HiveInitializationError(
+            HiveInitializationError::NodeDoesNotExist(args.node.clone()),
+        ))?;
+
+    let status = ssh_into_node(&name, node, &args.command, modifiers).await?;
+
+    if !status.success() {
+        // mirror ssh, which exits with the code of the remote command
+        std::process::exit(status.code().unwrap_or(1));
+    }
+
+    Ok(())
+}
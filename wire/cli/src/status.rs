@@ -0,0 +1,77 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+// Copyright 2024-2025 wire Contributors
+
+use std::collections::BTreeMap;
+
+use futures::StreamExt;
+use lib::SubCommandModifiers;
+use lib::hive::drift::{Drift, check_drift};
+use lib::hive::{Hive, HiveLocation};
+use miette::{Diagnostic, IntoDiagnostic, Result};
+use thiserror::Error;
+use tracing::error;
+
+use crate::apply::Selection;
+use crate::cli::StatusArgs;
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("{drifted} node(s) drifted, {unknown} node(s) could not be checked.")]
+#[diagnostic(help("Drifted nodes can be brought back in line with `wire apply`."))]
+struct DriftDetected {
+    drifted: usize,
+    unknown: usize,
+}
+
+pub async fn status(
+    hive: &mut Hive,
+    location: HiveLocation,
+    args: StatusArgs,
+    mut modifiers: SubCommandModifiers,
+) -> Result<()> {
+    let selection = Selection::new(&args.targets.on, &mut modifiers);
+    let location = &location;
+
+    let mut set = hive
+        .nodes
+        .iter_mut()
+        .filter(|(name, node)| selection.contains(name, node))
+        .map(|(name, node)| async move {
+            (
+                name.0.to_string(),
+                check_drift(location, name, node, modifiers).await,
+            )
+        })
+        .peekable();
+
+    if set.peek().is_none() {
+        error!("There are no nodes selected");
+    }
+
+    let results = futures::stream::iter(set)
+        .buffer_unordered(args.parallel)
+        .collect::<BTreeMap<_, _>>()
+        .await;
+
+    if args.json {
+        println!("{}", serde_json::to_string(&results).into_diagnostic()?);
+    } else {
+        for (name, drift) in &results {
+            println!("{name}: {drift}");
+        }
+    }
+
+    let drifted = results
+        .values()
+        .filter(|drift| matches!(drift, Drift::Drifted { .. }))
+        .count();
+    let unknown = results
+        .values()
+        .filter(|drift| matches!(drift, Drift::Unknown { .. }))
+        .count();
+
+    if drifted + unknown > 0 {
+        return Err(DriftDetected { drifted, unknown }.into());
+    }
+
+    Ok(())
+}
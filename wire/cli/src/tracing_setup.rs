@@ -9,6 +9,9 @@ use std::{
 
 use clap_verbosity_flag::{LogLevel, Verbosity};
 use lib::STDIN_CLOBBER_LOCK;
+use tracing::level_filters::LevelFilter;
+
+use crate::events::EventLayer;
 use owo_colors::{OwoColorize, Stream, Style};
 use tracing::{Level, Subscriber};
 use tracing_log::AsTrace;
@@ -240,9 +243,11 @@ where
 
 /// Set up logging for the application
 /// Uses `WireFieldFormat` if -v was never passed
-pub fn setup_logging<L: LogLevel>(verbosity: &Verbosity<L>) {
+/// Additionally prints JSON events to stdout if `events` is true
+pub fn setup_logging<L: LogLevel>(verbosity: &Verbosity<L>, events: bool) {
     let filter = verbosity.log_level_filter().as_trace();
-    let registry = tracing_subscriber::registry();
+    let registry = tracing_subscriber::registry()
+        .with(events.then(|| EventLayer.with_filter(LevelFilter::DEBUG)));
 
     if verbosity.is_present() {
         let layer = tracing_subscriber::fmt::layer()
@@ -1,6 +1,20 @@
 // SPDX-License-Identifier: AGPL-3.0-or-later
 // Copyright 2024-2025 wire Contributors
 
+/// Prefixes each `KeyResult` the agent prints. stdout may be a terminal, so
+/// each length-delimited frame is base64 encoded on a line of its own.
+pub const KEY_RESULT_PREFIX: &str = "wire-key-result=";
+
+/// Prefixes each `KeyState` the agent prints when ran with `--query`
+pub const KEY_STATE_PREFIX: &str = "wire-key-state=";
+
+/// Makes the agent report the state of each key instead of writing them
+pub const QUERY_ARGUMENT: &str = "--query";
+
+/// Makes the agent remove the keys it wrote which are missing from the
+/// `KeyManifest` it is sent
+pub const PRUNE_ARGUMENT: &str = "--prune";
+
 pub mod keys {
     include!(concat!(env!("OUT_DIR"), "/key_agent.keys.rs"));
 }
@@ -5,90 +5,430 @@
 use base64::Engine;
 use base64::prelude::BASE64_STANDARD;
 use futures_util::stream::StreamExt;
-use key_agent::keys::KeySpec;
+use key_agent::keys::key_result::Status;
+use key_agent::keys::{KeyManifest, KeyResult, KeySpec, KeyState};
+use key_agent::{KEY_RESULT_PREFIX, KEY_STATE_PREFIX, PRUNE_ARGUMENT, QUERY_ARGUMENT};
 use nix::unistd::{Group, User};
 use prost::Message;
 use prost::bytes::Bytes;
 use sha2::{Digest, Sha256};
-use std::os::unix::fs::PermissionsExt;
+use std::collections::BTreeSet;
 use std::os::unix::fs::chown;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
 use std::path::{Path, PathBuf};
-use tokio::fs::File;
+use tokio::fs::{File, OpenOptions};
 use tokio::io::AsyncWriteExt;
 use tokio_util::codec::{FramedRead, LengthDelimitedCodec};
 
-fn create_path(key_path: &Path) -> Result<(), anyhow::Error> {
+/// Destinations of the keys written by the agent, one per line
+const MANIFEST: &str = "/var/lib/wire/keys.manifest";
+
+fn read_manifest(manifest: &Path) -> Result<BTreeSet<String>, anyhow::Error> {
+    match std::fs::read_to_string(manifest) {
+        Ok(contents) => Ok(contents
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(ToString::to_string)
+            .collect()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(BTreeSet::new()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn write_manifest(manifest: &Path, destinations: &BTreeSet<String>) -> Result<(), anyhow::Error> {
+    let temporary = manifest.with_extension("manifest.wire-tmp");
+
+    std::fs::create_dir_all(manifest.parent().unwrap())?;
+    std::fs::write(
+        &temporary,
+        destinations
+            .iter()
+            .fold(String::new(), |mut contents, destination| {
+                contents.push_str(destination);
+                contents.push('\n');
+                contents
+            }),
+    )?;
+    std::fs::rename(&temporary, manifest)?;
+
+    Ok(())
+}
+
+/// Adds the keys written by the agent to the manifest. Keys are still written
+/// if the manifest cannot be, for example when the agent does not run as root.
+fn record_keys(manifest: &Path, written: Vec<String>) {
+    let result = read_manifest(manifest).and_then(|mut destinations| {
+        let previous = destinations.len();
+        destinations.extend(written);
+
+        if destinations.len() == previous {
+            return Ok(());
+        }
+
+        write_manifest(manifest, &destinations)
+    });
+
+    if let Err(err) = result {
+        eprintln!("Failed to record keys in {}: {err:#}", manifest.display());
+    }
+}
+
+/// Removes the keys in the manifest that are no longer declared. Only keys
+/// the agent wrote are in the manifest, so keys that are declared but were
+/// never written are not added to it.
+async fn prune(manifest: &Path, declared: KeyManifest) -> Result<Vec<KeyResult>, anyhow::Error> {
+    let declared = declared.destinations.into_iter().collect::<BTreeSet<_>>();
+    let (mut kept, stale): (BTreeSet<_>, BTreeSet<_>) = read_manifest(manifest)?
+        .into_iter()
+        .partition(|destination| declared.contains(destination));
+
+    let mut results = Vec::new();
+
+    for destination in stale {
+        match tokio::fs::remove_file(&destination).await {
+            Ok(()) => results.push(KeyResult {
+                destination,
+                status: Status::Removed.into(),
+                ..Default::default()
+            }),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => {
+                // attempted again by the next prune
+                kept.insert(destination.clone());
+
+                results.push(KeyResult {
+                    destination,
+                    status: Status::Failed.into(),
+                    reason: format!("failed to remove the key: {err}"),
+                    ..Default::default()
+                });
+            }
+        }
+    }
+
+    if let Err(err) = write_manifest(manifest, &kept) {
+        eprintln!("Failed to write {}: {err:#}", manifest.display());
+    }
+
+    Ok(results)
+}
+
+/// Resolves the uid and gid of the key. Missing users and groups fall back to
+/// root, unless the key has strict ownership.
+fn resolve_owner(spec: &KeySpec) -> Result<(u32, u32), anyhow::Error> {
+    let user = User::from_name(&spec.user)?;
+    let group = Group::from_name(&spec.group)?;
+
+    if spec.strict_ownership {
+        if user.is_none() {
+            return Err(anyhow::anyhow!(
+                "user {} does not exist, set strictOwnership = false to fall back to root",
+                spec.user
+            ));
+        }
+
+        if group.is_none() {
+            return Err(anyhow::anyhow!(
+                "group {} does not exist, set strictOwnership = false to fall back to root",
+                spec.group
+            ));
+        }
+    }
+
+    Ok((
+        user.map_or(0, |user| user.uid.into()),
+        group.map_or(0, |group| group.gid.into()),
+    ))
+}
+
+/// Creates the missing parents of the key, owned by the key's owner. Existing
+/// directories are left untouched.
+fn create_path(key_path: &Path, spec: &KeySpec, owner: (u32, u32)) -> Result<(), anyhow::Error> {
     let prefix = key_path.parent().unwrap();
-    std::fs::create_dir_all(prefix)?;
+    let missing = prefix
+        .ancestors()
+        .take_while(|directory| !directory.exists())
+        .collect::<Vec<_>>();
+
+    for directory in missing.into_iter().rev() {
+        std::fs::DirBuilder::new()
+            .mode(spec.directory_permissions)
+            .create(directory)?;
+
+        chown(directory, Some(owner.0), Some(owner.1))?;
+
+        // the mode given to `DirBuilder` is subject to the umask
+        std::fs::set_permissions(
+            directory,
+            std::fs::Permissions::from_mode(spec.directory_permissions),
+        )?;
+    }
 
     Ok(())
 }
 
-fn pretty_keyspec(spec: &KeySpec) -> String {
-    format!(
-        "{} {}:{} {}",
-        spec.destination, spec.user, spec.group, spec.permissions
-    )
+/// Writes the key next to its destination before renaming it into place, so
+/// that readers only ever see the previous or the complete key.
+async fn write_key(
+    spec: &KeySpec,
+    key_bytes: &[u8],
+    owner: (u32, u32),
+) -> Result<(), anyhow::Error> {
+    let path = PathBuf::from(&spec.destination);
+    create_path(&path, spec, owner)?;
+
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("{} is not a file", spec.destination))?;
+    let temporary = path.with_file_name(format!(".{}.wire-tmp", file_name.to_string_lossy()));
+
+    // left behind if a previous agent crashed mid-write
+    let _ = tokio::fs::remove_file(&temporary).await;
+
+    let result = async {
+        // only readable by root until the key is complete
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&temporary)
+            .await?;
+
+        file.write_all(key_bytes).await?;
+
+        chown(&temporary, Some(owner.0), Some(owner.1))?;
+
+        file.set_permissions(std::fs::Permissions::from_mode(spec.permissions))
+            .await?;
+        file.sync_all().await?;
+
+        tokio::fs::rename(&temporary, &path).await?;
+
+        // persist the rename itself
+        File::open(path.parent().unwrap()).await?.sync_all().await?;
+
+        Ok(())
+    }
+    .await;
+
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&temporary).await;
+    }
+
+    result
+}
+
+/// Digest, owner, and mode of the file at the key's destination
+async fn key_state(spec: &KeySpec) -> KeyState {
+    let mut state = KeyState {
+        destination: spec.destination.clone(),
+        ..Default::default()
+    };
+
+    let Ok(metadata) = tokio::fs::symlink_metadata(&spec.destination).await else {
+        return state;
+    };
+
+    if !metadata.is_file() {
+        return state;
+    }
+
+    state.uid = metadata.uid();
+    state.gid = metadata.gid();
+    state.mode = metadata.mode() & 0o7777;
+    state.metadata_matches = state.mode == spec.permissions
+        && resolve_owner(spec).is_ok_and(|owner| owner == (state.uid, state.gid));
+
+    if let Ok(contents) = tokio::fs::read(&spec.destination).await {
+        state.digest = Sha256::digest(contents).to_vec();
+    }
+
+    state
+}
+
+async fn apply_key(spec: &KeySpec, key_bytes: &[u8]) -> Result<KeyResult, anyhow::Error> {
+    let digest = Sha256::digest(key_bytes).to_vec();
+
+    if digest != spec.digest {
+        return Err(anyhow::anyhow!(
+            "digest of the key did not match {digest:?}! Please create an issue!"
+        ));
+    }
+
+    let owner = resolve_owner(spec)?;
+
+    let state = key_state(spec).await;
+
+    let status = if !spec.force && state.metadata_matches && state.digest == spec.digest {
+        Status::Unchanged
+    } else {
+        write_key(spec, key_bytes, owner).await?;
+        Status::Written
+    };
+
+    let metadata = tokio::fs::metadata(&spec.destination).await?;
+
+    Ok(KeyResult {
+        destination: spec.destination.clone(),
+        status: status.into(),
+        reason: String::new(),
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        mode: metadata.mode() & 0o7777,
+    })
+}
+
+fn report(prefix: &str, message: &impl Message) {
+    println!(
+        "{prefix}{}",
+        BASE64_STANDARD.encode(message.encode_length_delimited_to_vec())
+    );
 }
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
+    let query = std::env::args().any(|argument| argument == QUERY_ARGUMENT);
     let stdin = tokio::io::stdin();
 
     let mut framed = FramedRead::new(stdin, LengthDelimitedCodec::new());
 
+    if std::env::args().any(|argument| argument == PRUNE_ARGUMENT) {
+        let manifest_bytes = framed
+            .next()
+            .await
+            .expect("expected a manifest to be sent")?;
+
+        let declared = KeyManifest::decode(Bytes::from(BASE64_STANDARD.decode(manifest_bytes)?))?;
+
+        for result in prune(Path::new(MANIFEST), declared).await? {
+            report(KEY_RESULT_PREFIX, &result);
+        }
+
+        return Ok(());
+    }
+
+    let mut written = Vec::new();
+
     while let Some(spec_bytes) = framed.next().await {
         let spec_bytes = Bytes::from(BASE64_STANDARD.decode(spec_bytes?)?);
         let spec = KeySpec::decode(spec_bytes)?;
 
-        let key_bytes = BASE64_STANDARD.decode(
-            framed
-                .next()
-                .await
-                .expect("expected key_bytes to come after spec_bytes")?,
-        )?;
+        // only specs are sent when querying
+        if query {
+            report(KEY_STATE_PREFIX, &key_state(&spec).await);
+        } else {
+            let key_bytes = BASE64_STANDARD.decode(
+                framed
+                    .next()
+                    .await
+                    .expect("expected key_bytes to come after spec_bytes")?,
+            )?;
 
-        let digest = Sha256::digest(&key_bytes).to_vec();
+            // a failed key is reported, and the remaining keys are still written
+            let result = match apply_key(&spec, &key_bytes).await {
+                Ok(result) => result,
+                Err(err) => KeyResult {
+                    destination: spec.destination.clone(),
+                    status: Status::Failed.into(),
+                    reason: format!("{err:#}"),
+                    ..Default::default()
+                },
+            };
 
-        println!(
-            "Writing {}, {:?} bytes of data",
-            pretty_keyspec(&spec),
-            key_bytes.len()
-        );
+            if result.status() == Status::Written {
+                written.push(result.destination.clone());
+            }
 
-        if digest != spec.digest {
-            return Err(anyhow::anyhow!(
-                "digest of {spec:?} did not match {digest:?}! Please create an issue!"
-            ));
+            report(KEY_RESULT_PREFIX, &result);
         }
 
-        let path = PathBuf::from(&spec.destination);
-        create_path(&path)?;
+        // last key, goobye
+        if spec.last {
+            break;
+        }
+    }
 
-        let mut file = File::create(path).await?;
-        let mut permissions = file.metadata().await?.permissions();
+    if !query {
+        record_keys(Path::new(MANIFEST), written);
+    }
 
-        permissions.set_mode(spec.permissions);
-        file.set_permissions(permissions).await?;
+    Ok(())
+}
 
-        let user = User::from_name(&spec.user)?;
-        let group = Group::from_name(&spec.group)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        chown(
-            spec.destination,
-            // Default uid/gid to 0. This is then wrapped around an Option again for
-            // the function.
-            Some(user.map_or(0, |user| user.uid.into())),
-            Some(group.map_or(0, |group| group.gid.into())),
-        )?;
+    #[tokio::test]
+    async fn prune_only_written_keys() {
+        let directory =
+            std::env::temp_dir().join(format!("wire-key-agent-prune-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
 
-        file.write_all(&key_bytes).await?;
+        let manifest = directory.join("keys.manifest");
+        let path = |name: &str| directory.join(name).display().to_string();
+        let declare = |names: &[&str]| KeyManifest {
+            destinations: names.iter().map(|name| path(name)).collect(),
+        };
 
-        // last key, goobye
-        if spec.last {
-            break;
+        for name in ["written", "existing"] {
+            std::fs::write(path(name), name).unwrap();
         }
+
+        // "existing" is declared, but was never written by the agent
+        record_keys(&manifest, vec![path("written")]);
+        assert!(
+            prune(&manifest, declare(&["written", "existing"]))
+                .await
+                .unwrap()
+                .is_empty()
+        );
+
+        let results = prune(&manifest, declare(&[])).await.unwrap();
+
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].destination, path("written"));
+        assert_eq!(results[0].status(), Status::Removed);
+        assert!(!Path::new(&path("written")).exists());
+        assert!(Path::new(&path("existing")).exists());
+        assert!(read_manifest(&manifest).unwrap().is_empty());
+
+        std::fs::remove_dir_all(&directory).unwrap();
     }
 
-    Ok(())
+    #[tokio::test]
+    async fn force_rewrites_unchanged_key() {
+        let directory =
+            std::env::temp_dir().join(format!("wire-key-agent-force-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let user = User::from_uid(nix::unistd::getuid()).unwrap().unwrap();
+        let group = Group::from_gid(nix::unistd::getgid()).unwrap().unwrap();
+        let key_bytes = b"hello";
+
+        let mut spec = KeySpec {
+            destination: directory.join("key").display().to_string(),
+            user: user.name,
+            group: group.name,
+            permissions: 0o600,
+            directory_permissions: 0o755,
+            strict_ownership: true,
+            digest: Sha256::digest(key_bytes).to_vec(),
+            ..Default::default()
+        };
+
+        let status = async |spec: &KeySpec| apply_key(spec, key_bytes).await.unwrap().status();
+
+        assert_eq!(status(&spec).await, Status::Written);
+        assert_eq!(status(&spec).await, Status::Unchanged);
+
+        let inode = std::fs::metadata(&spec.destination).unwrap().ino();
+        spec.force = true;
+
+        assert_eq!(status(&spec).await, Status::Written);
+        // the key was replaced rather than left in place
+        assert_ne!(std::fs::metadata(&spec.destination).unwrap().ino(), inode);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
 }
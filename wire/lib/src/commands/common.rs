@@ -7,7 +7,9 @@ use tracing::instrument;
 
 use crate::{
     EvalGoal, SubCommandModifiers,
-    commands::{CommandArguments, Either, WireCommandChip, run_command, run_command_with_env},
+    commands::{
+        CommandArguments, Either, WireCommandChip, run_command, run_command_with_env, shell_join,
+    },
     errors::HiveLibError,
     hive::{
         HiveLocation,
@@ -16,19 +18,27 @@ use crate::{
 };
 
 pub async fn push(context: &Context<'_>, push: Push<'_>) -> Result<(), HiveLibError> {
-    let command_string = format!(
-        "nix --extra-experimental-features nix-command \
-        copy --substitute-on-destination --to ssh://{user}@{host} {path}",
-        user = context.node.target.user,
-        host = context.node.target.get_preferred_host()?,
-        path = match push {
-            Push::Derivation(drv) => format!("{drv} --derivation"),
-            Push::Path(path) => path.clone(),
-        }
-    );
+    let mut argv = vec![
+        "nix".to_string(),
+        "--extra-experimental-features".to_string(),
+        "nix-command".to_string(),
+        "copy".to_string(),
+        "--substitute-on-destination".to_string(),
+        "--to".to_string(),
+        format!(
+            "ssh://{user}@{host}",
+            user = context.node.target.user,
+            host = context.node.target.get_preferred_host()?,
+        ),
+    ];
+
+    match push {
+        Push::Derivation(drv) => argv.extend([drv.to_string(), "--derivation".to_string()]),
+        Push::Path(path) => argv.push(path.trim().to_string()),
+    }
 
     let child = run_command_with_env(
-        &CommandArguments::new(command_string, context.modifiers)
+        &CommandArguments::from_argv(argv, context.modifiers)
             .mode(crate::commands::ChildOutputMode::Nix),
         HashMap::from([(
             "NIX_SSHOPTS".into(),
@@ -60,47 +70,54 @@ pub async fn evaluate_hive_attribute(
     modifiers: SubCommandModifiers,
 ) -> Result<String, HiveLibError> {
     let attribute = match location {
-        HiveLocation::Flake(uri) => {
+        HiveLocation::Flake(uri) => vec![
+            format!("{uri}#wire"),
+            "--apply".to_string(),
             format!(
-                "{uri}#wire --apply \"hive: {}\"",
+                "hive: {}",
                 match goal {
                     EvalGoal::Inspect => "hive.inspect".to_string(),
                     EvalGoal::GetTopLevel(node) => format!("hive.topLevels.{node}"),
                 }
-            )
-        }
-        HiveLocation::HiveNix(path) => {
-            format!(
-                "--file {} {}",
-                &path.to_string_lossy(),
-                match goal {
-                    EvalGoal::Inspect => "inspect".to_string(),
-                    EvalGoal::GetTopLevel(node) => format!("topLevels.{node}"),
-                }
-            )
-        }
+            ),
+        ],
+        HiveLocation::HiveNix(path) => vec![
+            "--file".to_string(),
+            path.to_string_lossy().to_string(),
+            match goal {
+                EvalGoal::Inspect => "inspect".to_string(),
+                EvalGoal::GetTopLevel(node) => format!("topLevels.{node}"),
+            },
+        ],
     };
 
-    let command_string = format!(
-        "nix --extra-experimental-features nix-command \
-        --extra-experimental-features flakes \
-        eval --json {mods} {attribute}",
-        mods = if modifiers.show_trace {
-            "--show-trace"
-        } else {
-            ""
-        },
-    );
+    let mut argv = vec![
+        "nix",
+        "--extra-experimental-features",
+        "nix-command",
+        "--extra-experimental-features",
+        "flakes",
+        "eval",
+        "--json",
+    ];
+
+    if modifiers.show_trace {
+        argv.push("--show-trace");
+    }
+
+    argv.extend(attribute.iter().map(String::as_str));
 
     let child = run_command(
-        &CommandArguments::new(command_string, modifiers)
-            .mode(crate::commands::ChildOutputMode::Nix),
+        &CommandArguments::from_argv(argv, modifiers).mode(crate::commands::ChildOutputMode::Nix),
     )?;
 
     child
         .wait_till_success()
         .await
-        .map_err(|source| HiveLibError::NixEvalError { attribute, source })
+        .map_err(|source| HiveLibError::NixEvalError {
+            attribute: shell_join(&attribute),
+            source,
+        })
         .map(|x| match x {
             Either::Left((_, stdout)) | Either::Right((_, stdout)) => stdout,
         })
@@ -22,8 +22,8 @@ use std::{
 use tracing::instrument;
 use tracing::{Span, debug, error, trace, warn};
 
-use crate::commands::CommandArguments;
 use crate::commands::interactive_logbuffer::LogBuffer;
+use crate::commands::{CommandArguments, PrivilegeEscalation};
 use crate::errors::CommandError;
 use crate::{STDIN_CLOBBER_LOCK, SubCommandModifiers};
 use crate::{
@@ -36,6 +36,26 @@ type MasterWriter = Box<dyn Write + Send>;
 type MasterReader = Box<dyn Read + Send>;
 type Child = Box<dyn portable_pty::Child + Send + Sync>;
 
+/// Kills the child once dropped, unless it was disarmed after the child
+/// exited. Dropping the future waiting for a child, such as when its step
+/// times out, otherwise leaves the child, and its SSH session, running.
+struct KillOnDrop(Option<Box<dyn portable_pty::ChildKiller + Send + Sync>>);
+
+impl KillOnDrop {
+    fn disarm(&mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for KillOnDrop {
+    fn drop(&mut self) {
+        if let Some(mut killer) = self.0.take() {
+            debug!("Killing child that is no longer waited for");
+            let _ = killer.kill();
+        }
+    }
+}
+
 pub(crate) struct InteractiveChildChip {
     child: Child,
 
@@ -126,7 +146,7 @@ fn create_starting_segment<S: AsRef<str>>(
     }
 }
 
-#[instrument(skip_all, name = "run-int", fields(elevated = %arguments.elevated))]
+#[instrument(skip_all, name = "run-int", fields(elevated = arguments.elevated.is_some()))]
 pub(crate) fn interactive_command_with_env<S: AsRef<str>>(
     arguments: &CommandArguments<S>,
     envs: std::collections::HashMap<String, String>,
@@ -141,7 +161,7 @@ pub(crate) fn interactive_command_with_env<S: AsRef<str>>(
 
     let command_string = &format!(
         "{starting}{command} {flags} {IO_SUBS} && {ending}",
-        command = arguments.command_string.as_ref(),
+        command = arguments.command_string(),
         flags = match arguments.output_mode {
             ChildOutputMode::Nix => "--log-format internal-json",
             ChildOutputMode::Generic | ChildOutputMode::Interactive => "",
@@ -249,7 +269,7 @@ pub(crate) fn interactive_command_with_env<S: AsRef<str>>(
         write_stdin_pipe_w,
         stderr_collection,
         stdout_collection,
-        original_command: arguments.command_string.as_ref().to_string(),
+        original_command: arguments.command_string().into_owned(),
         completion_status,
         stdout_handle,
     })
@@ -258,12 +278,12 @@ pub(crate) fn interactive_command_with_env<S: AsRef<str>>(
 fn print_authenticate_warning<S: AsRef<str>>(
     arguments: &CommandArguments<S>,
 ) -> Result<(), HiveLibError> {
-    if !arguments.elevated {
+    let Some(prefix) = arguments.escalation().and_then(PrivilegeEscalation::prefix) else {
         return Ok(());
-    }
+    };
 
     eprintln!(
-        "{} | Authenticate for \"sudo {}\":",
+        "{} | Authenticate for \"{prefix} {}\":",
         arguments
             .target
             .map_or(Ok("localhost (!)".to_string()), |target| Ok(format!(
@@ -272,7 +292,7 @@ fn print_authenticate_warning<S: AsRef<str>>(
                 target.get_preferred_host()?,
                 target.port
             )))?,
-        arguments.command_string.as_ref()
+        arguments.command_string()
     );
 
     Ok(())
@@ -313,7 +333,7 @@ fn setup_master(pty_pair: &PtyPair) -> Result<(), HiveLibError> {
 
 fn build_command<S: AsRef<str>>(
     arguments: &CommandArguments<'_, S>,
-    command_string: &String,
+    command_string: &str,
 ) -> Result<CommandBuilder, HiveLibError> {
     let mut command = if let Some(target) = arguments.target {
         let mut command = create_sync_ssh_command(target, arguments.modifiers)?;
@@ -330,11 +350,7 @@ fn build_command<S: AsRef<str>>(
         command
     };
 
-    if arguments.elevated {
-        command.arg(format!("sudo -u root -- sh -c '{command_string}'"));
-    } else {
-        command.arg(command_string);
-    }
+    command.arg(arguments.wrap_elevated(command_string));
 
     Ok(command)
 }
@@ -376,10 +392,12 @@ impl WireCommandChip for InteractiveChildChip {
     async fn wait_till_success(mut self) -> Result<Self::ExitStatus, CommandError> {
         drop(self.write_stdin_pipe_w);
 
+        let mut kill_on_drop = KillOnDrop(Some(self.child.clone_killer()));
         let exit_status = tokio::task::spawn_blocking(move || self.child.wait())
             .await
             .map_err(CommandError::JoinError)?
             .map_err(CommandError::WaitForStatus)?;
+        kill_on_drop.disarm();
 
         debug!("exit_status: {exit_status:?}");
 
@@ -777,6 +795,24 @@ mod tests {
     use super::*;
     use std::{assert_matches::assert_matches, sync::mpsc::TryRecvError};
 
+    #[test]
+    fn kill_dropped_child() {
+        let pty_pair =
+            portable_pty::PtySystem::openpty(&NativePtySystem::default(), PtySize::default())
+                .unwrap();
+        let mut command = CommandBuilder::new("sleep");
+        command.arg("30");
+        let mut child = pty_pair.slave.spawn_command(command).unwrap();
+
+        let mut disarmed = KillOnDrop(Some(child.clone_killer()));
+        disarmed.disarm();
+        drop(disarmed);
+        assert_matches!(child.try_wait(), Ok(None));
+
+        drop(KillOnDrop(Some(child.clone_killer())));
+        assert!(!child.wait().unwrap().success());
+    }
+
     #[test]
     fn test_rawmode_data() {
         let aho_corasick = AhoCorasick::builder()
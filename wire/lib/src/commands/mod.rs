@@ -1,12 +1,14 @@
 // SPDX-License-Identifier: AGPL-3.0-or-later
 // Copyright 2024-2025 wire Contributors
 
-use std::{collections::HashMap, str::from_utf8, sync::LazyLock};
+use std::{borrow::Cow, collections::HashMap, str::from_utf8, sync::LazyLock};
 
 use aho_corasick::AhoCorasick;
 use gjson::Value;
+use nix::unistd::geteuid;
 use nix_compat::log::{AT_NIX_PREFIX, VerbosityLevel};
 use num_enum::TryFromPrimitive;
+use serde::{Deserialize, Serialize};
 use tracing::{debug, error, info, trace, warn};
 
 use crate::{
@@ -16,7 +18,7 @@ use crate::{
         noninteractive::{NonInteractiveChildChip, non_interactive_command_with_env},
     },
     errors::{CommandError, HiveLibError},
-    hive::node::Target,
+    hive::node::{Node, Target},
 };
 
 pub(crate) mod common;
@@ -37,14 +39,116 @@ pub enum Either<L, R> {
     Right(R),
 }
 
+/// `deployment.privilegeEscalation`, how elevated commands are ran as root
+#[derive(Serialize, Deserialize, Clone, Debug, Default, Eq, PartialEq, Hash)]
+#[serde(from = "String", into = "String")]
+pub enum PrivilegeEscalation {
+    #[default]
+    Sudo,
+    Doas,
+    Run0,
+    /// Elevated commands run as the target user
+    None,
+    /// A command prefix, such as `sudo -n`
+    Custom(String),
+}
+
+impl From<String> for PrivilegeEscalation {
+    fn from(value: String) -> Self {
+        match value.as_str() {
+            "sudo" => PrivilegeEscalation::Sudo,
+            "doas" => PrivilegeEscalation::Doas,
+            "run0" => PrivilegeEscalation::Run0,
+            "none" => PrivilegeEscalation::None,
+            _ => PrivilegeEscalation::Custom(value),
+        }
+    }
+}
+
+impl From<PrivilegeEscalation> for String {
+    fn from(value: PrivilegeEscalation) -> Self {
+        match value {
+            PrivilegeEscalation::Sudo => "sudo".to_string(),
+            PrivilegeEscalation::Doas => "doas".to_string(),
+            PrivilegeEscalation::Run0 => "run0".to_string(),
+            PrivilegeEscalation::None => "none".to_string(),
+            PrivilegeEscalation::Custom(prefix) => prefix,
+        }
+    }
+}
+
+impl PrivilegeEscalation {
+    /// Prefix running a command as root
+    fn prefix(&self) -> Option<&str> {
+        match self {
+            PrivilegeEscalation::Sudo => Some("sudo -u root --"),
+            PrivilegeEscalation::Doas => Some("doas -u root --"),
+            PrivilegeEscalation::Run0 => Some("run0 --user=root --"),
+            PrivilegeEscalation::None => None,
+            PrivilegeEscalation::Custom(prefix) => Some(prefix),
+        }
+    }
+
+    /// Wraps `command_string` to run as root
+    pub(crate) fn wrap(&self, command_string: &str) -> String {
+        match self.prefix() {
+            Some(prefix) => format!("{prefix} sh -c {}", shell_quote(command_string)),
+            None => format!("sh -c {}", shell_quote(command_string)),
+        }
+    }
+}
+
+/// Quotes `word` so that a POSIX shell reads it back as a single word,
+/// leaving words without special characters as they are
+pub(crate) fn shell_quote(word: &str) -> Cow<'_, str> {
+    let is_safe = |char: char| char.is_ascii_alphanumeric() || "@%+=:,./-_".contains(char);
+
+    if !word.is_empty() && word.chars().all(is_safe) {
+        return Cow::Borrowed(word);
+    }
+
+    Cow::Owned(format!("'{}'", word.replace('\'', r"'\''")))
+}
+
+/// Quotes and joins `argv` into a command a POSIX shell runs as is
+pub(crate) fn shell_join<I, A>(argv: I) -> String
+where
+    I: IntoIterator<Item = A>,
+    A: AsRef<str>,
+{
+    argv.into_iter()
+        .map(|word| shell_quote(word.as_ref()).into_owned())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The command a child runs
+#[derive(Debug)]
+pub(crate) enum CommandLine<S: AsRef<str>> {
+    /// Shell text, run as is
+    Shell(S),
+    /// The words of a single command, quoted once the command is handed to a
+    /// shell
+    Argv(Vec<String>),
+}
+
+impl<S: AsRef<str>> CommandLine<S> {
+    fn render(&self) -> Cow<'_, str> {
+        match self {
+            Self::Shell(command_string) => Cow::Borrowed(command_string.as_ref()),
+            Self::Argv(argv) => Cow::Owned(shell_join(argv)),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct CommandArguments<'t, S: AsRef<str>> {
     modifiers: SubCommandModifiers,
     target: Option<&'t Target>,
     output_mode: ChildOutputMode,
-    command_string: S,
+    command: CommandLine<S>,
     keep_stdin_open: bool,
-    elevated: bool,
+    elevated: Option<&'t PrivilegeEscalation>,
     log_stdout: bool,
 }
 
@@ -56,12 +160,35 @@ static AHO_CORASICK: LazyLock<AhoCorasick> = LazyLock::new(|| {
         .unwrap()
 });
 
+impl CommandArguments<'_, String> {
+    /// Runs `argv` as a single command, quoting each of its words
+    pub(crate) fn from_argv<I, A>(argv: I, modifiers: SubCommandModifiers) -> Self
+    where
+        I: IntoIterator<Item = A>,
+        A: AsRef<str>,
+    {
+        Self::with_command(
+            CommandLine::Argv(
+                argv.into_iter()
+                    .map(|word| word.as_ref().to_string())
+                    .collect(),
+            ),
+            modifiers,
+        )
+    }
+}
+
 impl<'a, S: AsRef<str>> CommandArguments<'a, S> {
+    /// Runs `command_string` as shell text
     pub(crate) const fn new(command_string: S, modifiers: SubCommandModifiers) -> Self {
+        Self::with_command(CommandLine::Shell(command_string), modifiers)
+    }
+
+    const fn with_command(command: CommandLine<S>, modifiers: SubCommandModifiers) -> Self {
         Self {
-            command_string,
+            command,
             keep_stdin_open: false,
-            elevated: false,
+            elevated: None,
             log_stdout: false,
             target: None,
             output_mode: ChildOutputMode::Generic,
@@ -84,8 +211,10 @@ impl<'a, S: AsRef<str>> CommandArguments<'a, S> {
         self
     }
 
-    pub(crate) const fn elevated(mut self) -> Self {
-        self.elevated = true;
+    /// Runs the command as root, using the `deployment.privilegeEscalation`
+    /// of `node`
+    pub(crate) const fn elevated(mut self, node: &'a Node) -> Self {
+        self.elevated = Some(&node.privilege_escalation);
         self
     }
 
@@ -93,6 +222,33 @@ impl<'a, S: AsRef<str>> CommandArguments<'a, S> {
         self.log_stdout = true;
         self
     }
+
+    /// The privilege escalation an elevated command goes through, which is
+    /// skipped when the command already runs as root
+    fn escalation(&self) -> Option<&PrivilegeEscalation> {
+        let escalation = self.elevated?;
+        let is_root = self
+            .target
+            .map_or_else(|| geteuid().is_root(), |target| &*target.user == "root");
+
+        (!is_root && escalation.prefix().is_some()).then_some(escalation)
+    }
+
+    /// The command as shell text, with the words of an argv quoted
+    fn command_string(&self) -> Cow<'_, str> {
+        self.command.render()
+    }
+
+    /// Wraps `command_string` to run as root if the command is elevated
+    fn wrap_elevated(&self, command_string: &str) -> String {
+        if self.elevated.is_none() {
+            return command_string.to_string();
+        }
+
+        self.escalation()
+            .unwrap_or(&PrivilegeEscalation::None)
+            .wrap(command_string)
+    }
 }
 
 pub(crate) fn run_command<S: AsRef<str>>(
@@ -229,3 +385,109 @@ impl ChildOutputMode {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn privilege_escalation() {
+        let mut node = Node {
+            privilege_escalation: PrivilegeEscalation::from("doas".to_string()),
+            ..Node::from_host("node-1")
+        };
+        node.target.user = "deploy".into();
+
+        let arguments = CommandArguments::new("true", SubCommandModifiers::default())
+            .on_target(Some(&node.target))
+            .elevated(&node);
+        assert_eq!(
+            arguments.wrap_elevated("id -u"),
+            "doas -u root -- sh -c 'id -u'"
+        );
+
+        let custom = Node {
+            privilege_escalation: PrivilegeEscalation::from("sudo -n".to_string()),
+            ..node.clone()
+        };
+        let arguments = CommandArguments::new("true", SubCommandModifiers::default())
+            .on_target(Some(&custom.target))
+            .elevated(&custom);
+        assert_eq!(arguments.wrap_elevated("id -u"), "sudo -n sh -c 'id -u'");
+
+        // escalation is skipped for root
+        let root = Node::from_host("node-1");
+        let arguments = CommandArguments::new("true", SubCommandModifiers::default())
+            .on_target(Some(&root.target))
+            .elevated(&root);
+        assert_eq!(arguments.escalation(), None);
+        assert_eq!(arguments.wrap_elevated("id -u"), "sh -c 'id -u'");
+
+        let arguments = CommandArguments::new("true", SubCommandModifiers::default())
+            .on_target(Some(&node.target));
+        assert_eq!(arguments.wrap_elevated("id -u"), "id -u");
+
+        assert_eq!(String::from(PrivilegeEscalation::Run0), "run0");
+    }
+
+    #[test]
+    fn command_lines() {
+        let arguments =
+            CommandArguments::from_argv(["readlink", "-f", "a b"], SubCommandModifiers::default());
+        assert!(matches!(&arguments.command, CommandLine::Argv(argv) if argv.len() == 3));
+        assert_eq!(arguments.command_string(), "readlink -f 'a b'");
+
+        let arguments = CommandArguments::new("a && b", SubCommandModifiers::default());
+        assert_eq!(arguments.command_string(), "a && b");
+    }
+
+    #[test]
+    fn quote_words() {
+        assert_eq!(
+            shell_quote("/nix/store/aaaa-nixos-system"),
+            "/nix/store/aaaa-nixos-system"
+        );
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote("$(reboot)"), "'$(reboot)'");
+        assert_eq!(
+            shell_join(["nix-env", "--set", "/nix/store/a b"]),
+            "nix-env --set '/nix/store/a b'"
+        );
+    }
+
+    #[test]
+    fn quote_hostile_words() {
+        let hostile = [
+            "it's",
+            "'; touch /tmp/wire-pwned; '",
+            "$(id)",
+            "`id`",
+            "a\"b",
+            "back\\slash",
+            "new\nline",
+            "*",
+            "",
+            "-n",
+        ];
+
+        let command = shell_join(["printf", "%s\\0"].into_iter().chain(hostile));
+
+        // once directly, and once more through the `sh -c` of an elevated
+        // command
+        for script in [command.clone(), PrivilegeEscalation::None.wrap(&command)] {
+            let output = std::process::Command::new("sh")
+                .args(["-c", &script])
+                .output()
+                .unwrap();
+
+            assert_eq!(
+                String::from_utf8(output.stdout)
+                    .unwrap()
+                    .split_terminator('\0')
+                    .collect::<Vec<_>>(),
+                hostile
+            );
+        }
+    }
+}
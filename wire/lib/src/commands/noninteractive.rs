@@ -31,7 +31,7 @@ pub(crate) struct NonInteractiveChildChip {
     stdin: ChildStdin,
 }
 
-#[instrument(skip_all, name = "run", fields(elevated = %arguments.elevated))]
+#[instrument(skip_all, name = "run", fields(elevated = arguments.elevated.is_some()))]
 pub(crate) fn non_interactive_command_with_env<S: AsRef<str>>(
     arguments: &CommandArguments<S>,
     envs: HashMap<String, String>,
@@ -48,18 +48,14 @@ pub(crate) fn non_interactive_command_with_env<S: AsRef<str>>(
 
     let command_string = format!(
         "{command_string}{extra}",
-        command_string = arguments.command_string.as_ref(),
+        command_string = arguments.command_string(),
         extra = match arguments.output_mode {
             ChildOutputMode::Generic | ChildOutputMode::Interactive => "",
             ChildOutputMode::Nix => " --log-format internal-json",
         }
     );
 
-    let command_string = if arguments.elevated {
-        format!("sudo -u root -- sh -c '{command_string}'")
-    } else {
-        command_string
-    };
+    let command_string = arguments.wrap_elevated(&command_string);
 
     debug!("{command_string}");
 
@@ -114,7 +110,7 @@ pub(crate) fn non_interactive_command_with_env<S: AsRef<str>>(
         stdout_collection,
         child,
         joinset,
-        original_command: arguments.command_string.as_ref().to_string(),
+        original_command: arguments.command_string().into_owned(),
         stdin,
     })
 }
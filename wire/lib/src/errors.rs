@@ -10,7 +10,10 @@ use nix_compat::flakeref::{FlakeRef, FlakeRefError};
 use thiserror::Error;
 use tokio::task::JoinError;
 
-use crate::hive::node::{Name, SwitchToConfigurationGoal};
+use crate::hive::{
+    node::{Name, SwitchToConfigurationGoal},
+    policy::StepKind,
+};
 
 #[cfg(debug_assertions)]
 const DOCS_URL: &str = "http://localhost:5173/reference/errors.html";
@@ -77,6 +80,13 @@ pub enum KeyError {
     )]
     #[error("Failed to parse key permissions")]
     ParseKeyPermissions(#[source] ParseIntError),
+
+    #[diagnostic(
+        code(wire::key::Rejected),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("the key agent failed to write the key: {0}")]
+    Rejected(String),
 }
 
 #[derive(Debug, Diagnostic, Error)]
@@ -87,6 +97,54 @@ pub enum ActivationError {
     )]
     #[error("failed to run switch-to-configuration {0} on node {1}")]
     SwitchToConfigurationError(SwitchToConfigurationGoal, Name, #[source] CommandError),
+
+    #[diagnostic(
+        code(wire::activation::HealthCheckFailed),
+        help("The node was rolled back to its previous generation."),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("health check `{check}` failed on node {name}")]
+    HealthCheckFailed {
+        name: Name,
+        check: String,
+        #[source]
+        source: Box<CommandError>,
+    },
+
+    #[diagnostic(
+        code(wire::activation::Unconfirmed),
+        help(
+            "The node will switch back to its previous generation once `deployment.magicRollback.timeout` elapses."
+        ),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("failed to confirm the activation of node {0}")]
+    Unconfirmed(Name),
+
+    #[diagnostic(
+        code(wire::activation::WatchdogArmed),
+        help(
+            "A previous activation is still waiting to be confirmed. Wait for `deployment.magicRollback.timeout` to elapse or stop the unit."
+        ),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("node {0} has a magic rollback watchdog armed by {1}")]
+    WatchdogArmed(Name, String),
+
+    #[diagnostic(
+        code(wire::activation::Declined),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("activation of node {0} was declined")]
+    Declined(Name),
+
+    #[diagnostic(
+        code(wire::activation::Prompt),
+        help("`--confirm` requires a terminal to prompt on."),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("failed to prompt for confirmation")]
+    Prompt(#[source] std::io::Error),
 }
 
 #[derive(Debug, Diagnostic, Error)]
@@ -147,6 +205,21 @@ pub enum HiveInitializationError {
     )]
     #[error("node {0} not exist in hive")]
     NodeDoesNotExist(String),
+
+    #[diagnostic(
+        code(wire::hive_init::UnknownDependency),
+        help("`deployment.dependsOn` must list node names, or tags prefixed with `@`."),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("node {name} depends on {dependency}, which does not exist in hive")]
+    UnknownDependency { name: Name, dependency: String },
+
+    #[diagnostic(
+        code(wire::hive_init::DependencyCycle),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("`deployment.dependsOn` forms a cycle between nodes {}", .0.join(", "))]
+    DependencyCycle(Vec<String>),
 }
 
 #[derive(Debug, Diagnostic, Error)]
@@ -276,6 +349,87 @@ pub enum CommandError {
     )]
     #[error("$XDG_RUNTIME_DIR could not be used.")]
     RuntimeDirectoryMissing(#[source] std::env::VarError),
+
+    #[diagnostic(
+        code(wire::command::KnownHosts),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("Failed to write the pinned host keys to $XDG_RUNTIME_DIR/wire")]
+    KnownHosts(#[source] std::io::Error),
+}
+
+#[derive(Debug, Diagnostic, Error)]
+pub enum JournalError {
+    #[diagnostic(
+        code(wire::journal::StateDirectoryMissing),
+        help("Set $XDG_STATE_HOME or $HOME to a writable directory."),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("neither $XDG_STATE_HOME nor $HOME could be used")]
+    StateDirectoryMissing(#[source] std::env::VarError),
+
+    #[diagnostic(
+        code(wire::journal::Io),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("failed to access the journal")]
+    Io(#[source] std::io::Error),
+
+    #[diagnostic(
+        code(wire::journal::Parse),
+        help("Run without `--resume` to start over."),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("failed to parse the journal")]
+    Parse(#[source] serde_json::Error),
+}
+
+#[derive(Debug, Diagnostic, Error)]
+pub enum PlanError {
+    #[diagnostic(
+        code(wire::plan::Stale),
+        help("The hive or its lock file changed since the plan was made. Run `wire plan` again."),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("the plan was made for hive {planned}, but the hive is now {current}")]
+    Stale { planned: String, current: String },
+
+    #[diagnostic(
+        code(wire::plan::KeyChanged),
+        help("Run `wire plan` again."),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("key {key} of node {name} changed since the plan was made")]
+    KeyChanged { name: Name, key: String },
+
+    #[diagnostic(
+        code(wire::plan::NodeDoesNotExist),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("node {0} of the plan does not exist in hive")]
+    NodeDoesNotExist(Name),
+
+    #[diagnostic(
+        code(wire::plan::Version),
+        help("Run `wire plan` again with this version of wire."),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("the plan has version {0}, which this version of wire cannot apply")]
+    Version(u32),
+
+    #[diagnostic(
+        code(wire::plan::Io),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("failed to access the plan file")]
+    Io(#[source] std::io::Error),
+
+    #[diagnostic(
+        code(wire::plan::Parse),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("failed to parse the plan file")]
+    Parse(#[source] serde_json::Error),
 }
 
 #[derive(Debug, Diagnostic, Error)]
@@ -300,6 +454,14 @@ pub enum HiveLibError {
     #[diagnostic(transparent)]
     HiveLocationError(HiveLocationError),
 
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    JournalError(JournalError),
+
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    PlanError(PlanError),
+
     #[error("Failed to apply key {}", .0)]
     KeyError(
         String,
@@ -308,6 +470,13 @@ pub enum HiveLibError {
         KeyError,
     ),
 
+    #[diagnostic(
+        code(wire::KeysFailed),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("Failed to apply {} keys", .0.len())]
+    KeysFailed(#[related] Vec<HiveLibError>),
+
     #[diagnostic(
         code(wire::BuildNode),
         url("{DOCS_URL}#{}", self.code().unwrap())
@@ -340,6 +509,33 @@ pub enum HiveLibError {
         source: CommandError,
     },
 
+    #[diagnostic(
+        code(wire::DependencyFailed),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("node {name} was skipped as its dependency {dependency} did not apply")]
+    DependencyFailed { name: Name, dependency: Name },
+
+    #[diagnostic(
+        code(wire::Cancelled),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("node {0} was cancelled as another node failed")]
+    Cancelled(Name),
+
+    #[diagnostic(
+        code(wire::StepTimedOut),
+        help("Raise `deployment.timeouts.{kind}`, or pass `--timeout {kind}=SECONDS`."),
+        url("{DOCS_URL}#{}", self.code().unwrap())
+    )]
+    #[error("`{step}` of node {name} timed out after {timeout}s")]
+    StepTimedOut {
+        name: Name,
+        step: String,
+        kind: StepKind,
+        timeout: u64,
+    },
+
     #[diagnostic(
         code(wire::Encoding),
         url("{DOCS_URL}#{}", self.code().unwrap())
@@ -0,0 +1,178 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+// Copyright 2024-2025 wire Contributors
+
+use std::fmt::Display;
+
+use serde::Serialize;
+use tracing::{debug, instrument};
+
+use crate::{
+    EvalGoal, HiveLibError, SubCommandModifiers,
+    commands::{
+        CommandArguments, Either, WireCommandChip, common::evaluate_hive_attribute, run_command,
+    },
+    errors::HiveInitializationError,
+    hive::{
+        HiveLocation,
+        node::{Name, Node, Target, should_apply_locally},
+        steps::cleanup::clean_up_control_master,
+    },
+};
+
+/// Whether the system running on a node matches what the hive evaluates to
+#[derive(Serialize, Clone, Debug, Eq, PartialEq)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+pub enum Drift {
+    InSync {
+        system: String,
+    },
+    /// The node is running something other than the hive's configuration,
+    /// for example after `nixos-rebuild` was run on it directly
+    Drifted {
+        expected: String,
+        running: String,
+    },
+    /// The hive could not be evaluated or the node could not be reached
+    Unknown {
+        reason: String,
+    },
+}
+
+impl Drift {
+    fn classify(expected: &str, running: &str) -> Self {
+        let (expected, running) = (expected.trim(), running.trim());
+
+        if expected == running {
+            Drift::InSync {
+                system: expected.to_string(),
+            }
+        } else {
+            Drift::Drifted {
+                expected: expected.to_string(),
+                running: running.to_string(),
+            }
+        }
+    }
+}
+
+impl Display for Drift {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Drift::InSync { .. } => write!(f, "in sync"),
+            Drift::Drifted { expected, running } => {
+                write!(f, "drifted (expected {expected}, running {running})")
+            }
+            Drift::Unknown { reason } => write!(f, "unknown ({reason})"),
+        }
+    }
+}
+
+async fn stdout_of(
+    argv: &[&str],
+    target: Option<&Target>,
+    modifiers: SubCommandModifiers,
+) -> Result<String, HiveLibError> {
+    let child = run_command(&CommandArguments::from_argv(argv, modifiers).on_target(target))?;
+
+    match child
+        .wait_till_success()
+        .await
+        .map_err(HiveLibError::CommandError)?
+    {
+        Either::Left((_, stdout)) | Either::Right((_, stdout)) => Ok(stdout),
+    }
+}
+
+/// Finds the output path of the node's toplevel without building it.
+async fn expected_system(
+    location: &HiveLocation,
+    name: &Name,
+    modifiers: SubCommandModifiers,
+) -> Result<String, HiveLibError> {
+    let output = evaluate_hive_attribute(location, &EvalGoal::GetTopLevel(name), modifiers).await?;
+    let derivation = serde_json::from_str::<String>(&output).map_err(|err| {
+        HiveLibError::HiveInitializationError(HiveInitializationError::ParseEvaluateError(err))
+    })?;
+
+    stdout_of(
+        &["nix-store", "--query", "--outputs", &derivation],
+        None,
+        modifiers,
+    )
+    .await
+}
+
+async fn running_system(
+    name: &Name,
+    node: &mut Node,
+    modifiers: SubCommandModifiers,
+) -> Result<String, HiveLibError> {
+    if should_apply_locally(node.allow_local_deployment, &name.0) {
+        return stdout_of(&["readlink", "-f", "/run/current-system"], None, modifiers).await;
+    }
+
+    node.ping_with_failover(modifiers).await?;
+
+    let running = stdout_of(
+        &["readlink", "-f", "/run/current-system"],
+        Some(&node.target),
+        modifiers,
+    )
+    .await;
+
+    let _ = clean_up_control_master(node, modifiers).await;
+
+    running
+}
+
+/// Compares the node's evaluated toplevel against its `/run/current-system`
+#[instrument(skip_all, name = "drift", fields(node = %name))]
+pub async fn check_drift(
+    location: &HiveLocation,
+    name: &Name,
+    node: &mut Node,
+    modifiers: SubCommandModifiers,
+) -> Drift {
+    let result = async {
+        let expected = expected_system(location, name, modifiers).await?;
+        let running = running_system(name, node, modifiers).await?;
+
+        Ok::<_, HiveLibError>(Drift::classify(&expected, &running))
+    }
+    .await;
+
+    debug!(result = ?result);
+
+    result.unwrap_or_else(|error| Drift::Unknown {
+        reason: error.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify() {
+        assert_eq!(
+            Drift::classify(
+                "/nix/store/aaaa-nixos-system-node\n",
+                "/nix/store/aaaa-nixos-system-node"
+            ),
+            Drift::InSync {
+                system: "/nix/store/aaaa-nixos-system-node".to_string()
+            }
+        );
+
+        assert_eq!(
+            Drift::classify(
+                "/nix/store/aaaa-nixos-system-node",
+                "/nix/store/bbbb-nixos-system-node"
+            ),
+            Drift::Drifted {
+                expected: "/nix/store/aaaa-nixos-system-node".to_string(),
+                running: "/nix/store/bbbb-nixos-system-node".to_string()
+            }
+        );
+    }
+}
@@ -0,0 +1,180 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+// Copyright 2024-2025 wire Contributors
+
+use std::fmt::Display;
+use std::process::ExitStatus;
+use std::sync::Arc;
+
+use serde::Serialize;
+use tracing::{debug, instrument};
+
+use crate::{
+    HiveLibError, SubCommandModifiers,
+    commands::{CommandArguments, Either, WireCommandChip, run_command, shell_join},
+    errors::CommandError,
+    hive::{
+        node::{Name, Node, should_apply_locally},
+        steps::cleanup::clean_up_control_master,
+    },
+};
+
+/// Printed after the command, so that its exit code can be told apart from
+/// wire failing to run it
+const EXIT_CODE_PREFIX: &str = "wire-exit-code=";
+
+#[derive(Serialize, Clone, Debug, Eq, PartialEq)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+pub enum ExecResult {
+    /// The command ran on `host`. `output` holds both its stdout and stderr.
+    Exited {
+        host: Arc<str>,
+        code: i32,
+        output: String,
+    },
+    /// The command could not be ran, for example as the node was unreachable
+    Failed { reason: String },
+}
+
+impl Display for ExecResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExecResult::Exited { code, .. } => write!(f, "exited {code}"),
+            ExecResult::Failed { reason } => write!(f, "failed ({reason})"),
+        }
+    }
+}
+
+/// Wraps `command` so that it always succeeds, printing its exit code last.
+fn wrap(command: &str) -> String {
+    format!("{{ {{ {command}\n}} 2>&1; echo \"{EXIT_CODE_PREFIX}$?\"; }}")
+}
+
+/// Splits the output of a wrapped command into its exit code and output
+fn parse(stdout: &str) -> Option<(i32, String)> {
+    let (output, code) = stdout.trim_end().rsplit_once(EXIT_CODE_PREFIX)?;
+
+    Some((code.trim().parse().ok()?, output.trim_end().to_string()))
+}
+
+async fn run(
+    node: &Node,
+    local: bool,
+    command: &[String],
+    elevated: bool,
+    modifiers: SubCommandModifiers,
+) -> Result<String, HiveLibError> {
+    let mut arguments = CommandArguments::new(wrap(&shell_join(command)), modifiers)
+        .on_target(if local { None } else { Some(&node.target) });
+
+    if elevated {
+        arguments = arguments.elevated(node);
+    }
+
+    match run_command(&arguments)?
+        .wait_till_success()
+        .await
+        .map_err(HiveLibError::CommandError)?
+    {
+        Either::Left((_, stdout)) | Either::Right((_, stdout)) => Ok(stdout),
+    }
+}
+
+/// Runs `command` on the node, trying each of its hosts in order. Each word of
+/// `command` is passed to the node as is.
+#[instrument(skip_all, name = "exec", fields(node = %name))]
+pub async fn exec(
+    name: &Name,
+    node: &mut Node,
+    command: &[String],
+    elevated: bool,
+    modifiers: SubCommandModifiers,
+) -> ExecResult {
+    let local = should_apply_locally(node.allow_local_deployment, &name.0);
+
+    let result = async {
+        let host = if local {
+            Arc::from("localhost")
+        } else {
+            node.ping_with_failover(modifiers).await?
+        };
+
+        let stdout = run(node, local, command, elevated, modifiers).await;
+
+        if !local {
+            let _ = clean_up_control_master(node, modifiers).await;
+        }
+
+        Ok::<_, HiveLibError>((host, stdout?))
+    }
+    .await;
+
+    debug!(result = ?result);
+
+    match result {
+        Ok((host, stdout)) => match parse(&stdout) {
+            Some((code, output)) => ExecResult::Exited { host, code, output },
+            None => ExecResult::Failed {
+                reason: "the exit code of the command was not printed".to_string(),
+            },
+        },
+        Err(error) => ExecResult::Failed {
+            reason: error.to_string(),
+        },
+    }
+}
+
+/// Logs into the first reachable host of the node with the user's terminal,
+/// running `command` if it is not empty
+#[instrument(skip_all, name = "ssh", fields(node = %name))]
+pub async fn ssh(
+    name: &Name,
+    node: &mut Node,
+    command: &[String],
+    modifiers: SubCommandModifiers,
+) -> Result<ExitStatus, HiveLibError> {
+    node.ping_with_failover(modifiers).await?;
+
+    let status = node
+        .target
+        .create_interactive_ssh_command(modifiers, command)?
+        .status()
+        .await
+        .map_err(|err| HiveLibError::CommandError(CommandError::WaitForStatus(err)));
+
+    let _ = clean_up_control_master(node, modifiers).await;
+
+    status
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::PrivilegeEscalation;
+
+    #[test]
+    fn parse_output() {
+        assert_eq!(
+            parse("foo.service\nbar.service\nwire-exit-code=3\n"),
+            Some((3, "foo.service\nbar.service".to_string()))
+        );
+
+        assert_eq!(parse("wire-exit-code=0"), Some((0, String::new())));
+        assert_eq!(parse("no exit code"), None);
+    }
+
+    #[test]
+    fn wrap_elevated() {
+        let wrapped = wrap("echo 'hi'");
+
+        assert_eq!(
+            wrapped,
+            "{ { echo 'hi'\n} 2>&1; echo \"wire-exit-code=$?\"; }"
+        );
+
+        // the command is quoted once more when ran as root
+        assert_eq!(
+            PrivilegeEscalation::Sudo.wrap(&wrapped),
+            "sudo -u root -- sh -c '{ { echo '\\''hi'\\''\n} 2>&1; echo \"wire-exit-code=$?\"; }'"
+        );
+    }
+}
@@ -0,0 +1,204 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+// Copyright 2024-2025 wire Contributors
+
+use std::fmt::Display;
+use std::sync::Arc;
+
+use serde::Serialize;
+use tracing::{instrument, warn};
+
+use crate::{
+    HiveLibError, SubCommandModifiers,
+    commands::{CommandArguments, Either, WireCommandChip, run_command},
+    hive::{
+        node::{Name, Node, should_apply_locally},
+        steps::cleanup::clean_up_control_master,
+    },
+};
+
+/// Prints the current system, `NixOS` version, kernel, and uptime, followed by
+/// the booted and current path of every component that requires a reboot to
+/// change.
+const FACTS_SCRIPT: &str = "{ readlink -f /run/current-system; \
+    cat /run/current-system/nixos-version; \
+    uname -r; \
+    cut -d. -f1 /proc/uptime; \
+    for link in kernel initrd kernel-modules; do \
+    readlink -f /run/booted-system/$link /run/current-system/$link; \
+    done; }";
+
+#[derive(Serialize, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct Facts {
+    pub current_system: String,
+    pub nixos_version: String,
+    pub kernel: String,
+    pub uptime_seconds: u64,
+    /// The kernel, initrd, or kernel modules differ between the booted and
+    /// current system
+    pub reboot_required: bool,
+}
+
+#[derive(Serialize, Clone, Debug, Eq, PartialEq, Hash)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum Liveness {
+    /// The node answered on `host`. `facts` is empty if they could not be
+    /// gathered.
+    Online {
+        host: Arc<str>,
+        facts: Option<Facts>,
+    },
+    Offline,
+}
+
+impl Display for Liveness {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Liveness::Online { host, .. } => write!(f, "online via {host}"),
+            Liveness::Offline => write!(f, "offline"),
+        }
+    }
+}
+
+impl Facts {
+    fn parse(output: &str) -> Option<Self> {
+        let output = output.lines().map(str::trim).collect::<Vec<_>>();
+        let [current_system, nixos_version, kernel, uptime, links @ ..] = output.as_slice() else {
+            return None;
+        };
+
+        if links.is_empty() || links.len() % 2 != 0 {
+            return None;
+        }
+
+        Some(Facts {
+            current_system: (*current_system).to_string(),
+            nixos_version: (*nixos_version).to_string(),
+            kernel: (*kernel).to_string(),
+            uptime_seconds: uptime.parse().ok()?,
+            reboot_required: links.chunks_exact(2).any(|pair| pair[0] != pair[1]),
+        })
+    }
+}
+
+#[must_use]
+pub fn format_uptime(seconds: u64) -> String {
+    let days = seconds / 86400;
+    let hours = seconds % 86400 / 3600;
+    let minutes = seconds % 3600 / 60;
+
+    if days > 0 {
+        format!("{days}d {hours}h {minutes}m")
+    } else if hours > 0 {
+        format!("{hours}h {minutes}m")
+    } else {
+        format!("{minutes}m")
+    }
+}
+
+async fn query_facts(
+    node: &Node,
+    local: bool,
+    modifiers: SubCommandModifiers,
+) -> Result<Option<Facts>, HiveLibError> {
+    let child =
+        run_command(
+            &CommandArguments::from_argv(["sh", "-c", FACTS_SCRIPT], modifiers)
+                .on_target(if local { None } else { Some(&node.target) }),
+        )?;
+
+    let stdout = match child
+        .wait_till_success()
+        .await
+        .map_err(HiveLibError::CommandError)?
+    {
+        Either::Left((_, stdout)) | Either::Right((_, stdout)) => stdout,
+    };
+
+    Ok(Facts::parse(&stdout))
+}
+
+/// Pings the node, trying each of its hosts in order, and gathers facts from
+/// the first one that answers.
+#[instrument(skip_all, name = "facts", fields(node = %name))]
+pub(crate) async fn gather_liveness(
+    name: &Name,
+    node: &mut Node,
+    modifiers: SubCommandModifiers,
+) -> Liveness {
+    let local = should_apply_locally(node.allow_local_deployment, &name.0);
+
+    let host = if local {
+        Arc::from("localhost")
+    } else {
+        match node.ping_with_failover(modifiers).await {
+            Ok(host) => host,
+            Err(error) => {
+                warn!("{name} is offline: {error}");
+                return Liveness::Offline;
+            }
+        }
+    };
+
+    let facts = query_facts(node, local, modifiers)
+        .await
+        .inspect_err(|error| warn!("Failed to gather facts from {name}: {error}"))
+        .ok()
+        .flatten();
+
+    if !local {
+        let _ = clean_up_control_master(node, modifiers).await;
+    }
+
+    Liveness::Online { host, facts }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::assert_matches::assert_matches;
+
+    #[test]
+    fn parse_facts() {
+        let output = "\
+/nix/store/aaaa-nixos-system-node-25.05
+25.05.20250101.abcdef (Warbler)
+6.12.3
+93784
+/nix/store/bbbb-linux-6.6.1/bzImage
+/nix/store/cccc-linux-6.12.3/bzImage
+/nix/store/dddd-initrd/initrd
+/nix/store/dddd-initrd/initrd
+/nix/store/eeee-kernel-modules/lib
+/nix/store/eeee-kernel-modules/lib";
+
+        assert_eq!(
+            Facts::parse(output),
+            Some(Facts {
+                current_system: "/nix/store/aaaa-nixos-system-node-25.05".to_string(),
+                nixos_version: "25.05.20250101.abcdef (Warbler)".to_string(),
+                kernel: "6.12.3".to_string(),
+                uptime_seconds: 93784,
+                reboot_required: true,
+            })
+        );
+
+        let unchanged = output.replace("bbbb-linux-6.6.1", "cccc-linux-6.12.3");
+        assert_matches!(
+            Facts::parse(&unchanged),
+            Some(Facts {
+                reboot_required: false,
+                ..
+            })
+        );
+
+        assert_eq!(Facts::parse("/nix/store/aaaa\n25.05\n6.12.3"), None);
+        assert_eq!(Facts::parse(&output.replace("93784", "not a number")), None);
+    }
+
+    #[test]
+    fn uptime() {
+        assert_eq!(format_uptime(59), "0m");
+        assert_eq!(format_uptime(3660), "1h 1m");
+        assert_eq!(format_uptime(93784), "1d 2h 3m");
+    }
+}
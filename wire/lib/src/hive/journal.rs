@@ -0,0 +1,260 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+// Copyright 2024-2025 wire Contributors
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{debug, warn};
+
+use crate::{
+    HiveLibError,
+    errors::JournalError,
+    hive::{
+        HiveLocation,
+        node::{Goal, Name, StepState},
+    },
+};
+
+/// Bumped whenever the journal changes in a way older versions of wire cannot
+/// resume from.
+const JOURNAL_VERSION: u32 = 1;
+
+/// Progress of a single node
+#[derive(Serialize, Deserialize, Clone, Debug, Default, Eq, PartialEq)]
+pub struct JournalEntry {
+    /// Steps that completed, in order
+    pub completed: Vec<String>,
+    pub derivation: Option<String>,
+    pub build: Option<String>,
+    /// Every step of the node completed
+    pub finished: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct JournalFile {
+    version: u32,
+    location: String,
+    goal: String,
+    nodes: HashMap<Name, JournalEntry>,
+}
+
+/// Records the progress of each node of an apply to
+/// `$XDG_STATE_HOME/wire/journal`, so that an interrupted apply can be
+/// resumed.
+pub struct Journal {
+    path: PathBuf,
+    file: Mutex<JournalFile>,
+}
+
+fn describe(location: &HiveLocation) -> String {
+    match location {
+        HiveLocation::HiveNix(path) => path.display().to_string(),
+        HiveLocation::Flake(flake) => flake.clone(),
+    }
+}
+
+fn get_journal_directory() -> Result<PathBuf, JournalError> {
+    let state_home = env::var("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|_| env::var("HOME").map(|home| PathBuf::from(home).join(".local/state")))
+        .map_err(JournalError::StateDirectoryMissing)?;
+
+    Ok(state_home.join("wire").join("journal"))
+}
+
+impl Journal {
+    /// Opens the journal of applying `goal` to the hive at `location`. The
+    /// previous journal is discarded unless `resume` is set.
+    pub fn open(location: &HiveLocation, goal: Goal, resume: bool) -> Result<Self, HiveLibError> {
+        let directory = get_journal_directory().map_err(HiveLibError::JournalError)?;
+
+        Self::open_in(&directory, location, goal, resume)
+    }
+
+    /// Opens the journal like [`Journal::open`], keeping it in `directory`
+    fn open_in(
+        directory: &Path,
+        location: &HiveLocation,
+        goal: Goal,
+        resume: bool,
+    ) -> Result<Self, HiveLibError> {
+        let location = describe(location);
+        let goal = goal.to_string();
+
+        let key = Sha256::digest(format!("{location}\n{goal}"));
+        let name = key.iter().take(16).fold(String::new(), |mut name, byte| {
+            let _ = write!(name, "{byte:02x}");
+            name
+        });
+
+        std::fs::create_dir_all(directory)
+            .map_err(|err| HiveLibError::JournalError(JournalError::Io(err)))?;
+        let path = directory.join(format!("{name}.json"));
+
+        let previous = if resume {
+            Self::read(&path).map_err(HiveLibError::JournalError)?
+        } else {
+            None
+        };
+
+        if resume && previous.is_none() {
+            warn!("There is no journal to resume from, applying every node");
+        }
+
+        let file = previous.unwrap_or_else(|| JournalFile {
+            version: JOURNAL_VERSION,
+            location,
+            goal,
+            nodes: HashMap::new(),
+        });
+
+        debug!("Using journal at {}", path.display());
+
+        let journal = Journal {
+            path,
+            file: Mutex::new(file),
+        };
+
+        // discards the previous journal straight away when starting over
+        journal
+            .persist(&journal.file.lock().unwrap())
+            .map_err(HiveLibError::JournalError)?;
+
+        Ok(journal)
+    }
+
+    fn read(path: &PathBuf) -> Result<Option<JournalFile>, JournalError> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(JournalError::Io(err)),
+        };
+
+        let file = serde_json::from_str::<JournalFile>(&contents).map_err(JournalError::Parse)?;
+
+        if file.version != JOURNAL_VERSION {
+            warn!("Ignoring journal written by an incompatible version of wire");
+            return Ok(None);
+        }
+
+        Ok(Some(file))
+    }
+
+    /// Writes the journal to a temporary file before renaming it over the
+    /// previous one, so an interrupted write never leaves a truncated journal
+    fn persist(&self, file: &JournalFile) -> Result<(), JournalError> {
+        let temporary = self.path.with_extension("json.tmp");
+        let contents = serde_json::to_vec(file).map_err(JournalError::Parse)?;
+
+        std::fs::write(&temporary, contents).map_err(JournalError::Io)?;
+        std::fs::rename(&temporary, &self.path).map_err(JournalError::Io)
+    }
+
+    fn update(&self, name: &Name, update: impl FnOnce(&mut JournalEntry)) {
+        let mut file = self.file.lock().unwrap();
+        update(file.nodes.entry(name.clone()).or_default());
+
+        if let Err(err) = self.persist(&file) {
+            warn!("Failed to write the journal: {err}");
+        }
+    }
+
+    #[must_use]
+    pub fn entry(&self, name: &Name) -> Option<JournalEntry> {
+        self.file.lock().unwrap().nodes.get(name).cloned()
+    }
+
+    /// Whether every step of the node completed in a previous apply
+    #[must_use]
+    pub fn finished(&self, name: &Name) -> bool {
+        self.entry(name).is_some_and(|entry| entry.finished)
+    }
+
+    pub(crate) fn record_step(&self, name: &Name, step: String, state: &StepState) {
+        self.update(name, |entry| {
+            entry.completed.push(step);
+
+            if let Some(derivation) = &state.evaluation {
+                entry.derivation = Some(derivation.to_string());
+            }
+
+            if let Some(build) = &state.build {
+                entry.build = Some(build.clone());
+            }
+        });
+    }
+
+    pub(crate) fn record_started(&self, name: &Name) {
+        self.update(name, |entry| {
+            entry.completed.clear();
+            entry.finished = false;
+        });
+    }
+
+    pub(crate) fn record_finished(&self, name: &Name) {
+        self.update(name, |entry| entry.finished = true);
+    }
+
+    /// Deletes the journal, once there is nothing left to resume
+    pub fn remove(&self) -> Result<(), HiveLibError> {
+        match std::fs::remove_file(&self.path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                Err(HiveLibError::JournalError(JournalError::Io(err)))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hive::node::SwitchToConfigurationGoal;
+
+    #[test]
+    fn resume() {
+        let tmp = tempdir::TempDir::new("wire-journal").unwrap();
+        let directory = tmp.path().join("journal");
+
+        let location = HiveLocation::Flake("github:foo/bar".to_string());
+        let goal = Goal::SwitchToConfiguration(SwitchToConfigurationGoal::Switch);
+        let name = Name("node-1".into());
+        let state = StepState {
+            build: Some("/nix/store/aaaa-nixos-system-node-1".to_string()),
+            ..Default::default()
+        };
+
+        let journal = Journal::open_in(&directory, &location, goal, false).unwrap();
+        journal.record_started(&name);
+        journal.record_step(&name, "Build the node".to_string(), &state);
+        drop(journal);
+
+        let journal = Journal::open_in(&directory, &location, goal, true).unwrap();
+        assert_eq!(
+            journal.entry(&name),
+            Some(JournalEntry {
+                completed: vec!["Build the node".to_string()],
+                derivation: None,
+                build: Some("/nix/store/aaaa-nixos-system-node-1".to_string()),
+                finished: false,
+            })
+        );
+
+        journal.record_finished(&name);
+        assert!(journal.finished(&name));
+
+        // a different goal has its own journal
+        let journal = Journal::open_in(&directory, &location, Goal::Build, true).unwrap();
+        assert_eq!(journal.entry(&name), None);
+
+        // not resuming starts over
+        let journal = Journal::open_in(&directory, &location, goal, false).unwrap();
+        assert_eq!(journal.entry(&name), None);
+        journal.remove().unwrap();
+    }
+}
@@ -7,8 +7,8 @@ use node::{Name, Node};
 use owo_colors::{OwoColorize, Stream};
 use serde::de::Error;
 use serde::{Deserialize, Deserializer, Serialize};
-use std::collections::HashMap;
 use std::collections::hash_map::OccupiedEntry;
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsStr;
 use std::fmt::Display;
 use std::fs;
@@ -19,8 +19,15 @@ use tracing::{info, instrument};
 
 use crate::commands::common::evaluate_hive_attribute;
 use crate::errors::{HiveInitializationError, HiveLocationError};
+use crate::hive::facts::{Liveness, format_uptime, gather_liveness};
 use crate::{EvalGoal, HiveLibError, SubCommandModifiers};
+pub mod drift;
+pub mod exec;
+pub mod facts;
+pub mod journal;
 pub mod node;
+pub mod plan;
+pub mod policy;
 pub mod steps;
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
@@ -83,6 +90,80 @@ impl Hive {
 
         Ok(())
     }
+
+    /// Resolves `deployment.dependsOn` of every node into the names of the
+    /// nodes it must be applied after.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a dependency does not exist in the hive, or if
+    /// the dependencies form a cycle.
+    pub fn dependencies(&self) -> Result<HashMap<Name, HashSet<Name>>, HiveLibError> {
+        let mut resolved = HashMap::with_capacity(self.nodes.len());
+
+        for (name, node) in &self.nodes {
+            let mut dependencies = HashSet::new();
+
+            for dependency in &node.depends_on {
+                if let Some(tag) = dependency.strip_prefix('@') {
+                    dependencies.extend(
+                        self.nodes
+                            .iter()
+                            .filter(|(other, node)| *other != name && node.tags.contains(tag))
+                            .map(|(other, _)| other.clone()),
+                    );
+                } else {
+                    let other = Name(Arc::from(dependency.as_str()));
+
+                    if !self.nodes.contains_key(&other) {
+                        return Err(HiveLibError::HiveInitializationError(
+                            HiveInitializationError::UnknownDependency {
+                                name: name.clone(),
+                                dependency: dependency.clone(),
+                            },
+                        ));
+                    }
+
+                    dependencies.insert(other);
+                }
+            }
+
+            resolved.insert(name.clone(), dependencies);
+        }
+
+        // Kahn's algorithm, anything that can never be scheduled is part of
+        // (or depends on) a cycle
+        let mut remaining = resolved.clone();
+        while let Some(ready) = remaining
+            .iter()
+            .find(|(_, dependencies)| dependencies.is_empty())
+            .map(|(name, _)| name.clone())
+        {
+            remaining.remove(&ready);
+            for dependencies in remaining.values_mut() {
+                dependencies.remove(&ready);
+            }
+        }
+
+        if !remaining.is_empty() {
+            return Err(HiveLibError::HiveInitializationError(
+                HiveInitializationError::DependencyCycle(
+                    remaining.keys().map(ToString::to_string).sorted().collect(),
+                ),
+            ));
+        }
+
+        Ok(resolved)
+    }
+
+    /// Pings every node in parallel, recording whether it is online and the
+    /// facts gathered from it.
+    pub async fn gather_facts(&mut self, modifiers: SubCommandModifiers) {
+        futures::future::join_all(self.nodes.iter_mut().map(|(name, node)| async move {
+            node.liveness = Some(gather_liveness(name, node, modifiers).await);
+        }))
+        .await;
+    }
 }
 
 impl Display for Hive {
@@ -105,6 +186,22 @@ impl Display for Hive {
             write!(f, " > {}", "Connection:".bold())?;
             writeln!(f, " {{{}}}", node.target)?;
 
+            if let Some(liveness) = &node.liveness {
+                write!(f, " > {}", "Liveness:".bold())?;
+                writeln!(f, " {liveness}")?;
+
+                if let Liveness::Online {
+                    facts: Some(facts), ..
+                } = liveness
+                {
+                    writeln!(f, "    > System: {}", facts.current_system)?;
+                    writeln!(f, "    > NixOS: {}", facts.nixos_version)?;
+                    writeln!(f, "    > Kernel: {}", facts.kernel)?;
+                    writeln!(f, "    > Uptime: {}", format_uptime(facts.uptime_seconds))?;
+                    writeln!(f, "    > Reboot required: {}", facts.reboot_required)?;
+                }
+            }
+
             write!(
                 f,
                 " > {} {}{}",
@@ -226,6 +323,102 @@ mod tests {
     use super::*;
     use std::{assert_matches::assert_matches, env};
 
+    fn hive_of(nodes: Vec<(&str, Node)>) -> Hive {
+        Hive {
+            nodes: nodes
+                .into_iter()
+                .map(|(name, node)| (Name(name.into()), node))
+                .collect(),
+            schema: Hive::SCHEMA_VERSION,
+        }
+    }
+
+    #[test]
+    fn dependencies_resolve_names_and_tags() {
+        let hive = hive_of(vec![
+            (
+                "db-primary",
+                Node {
+                    tags: im::hashset!["database".to_string()],
+                    ..Default::default()
+                },
+            ),
+            (
+                "db-replica",
+                Node {
+                    tags: im::hashset!["database".to_string()],
+                    depends_on: vec!["@database".to_string()],
+                    ..Default::default()
+                },
+            ),
+            (
+                "app",
+                Node {
+                    depends_on: vec!["@database".to_string(), "db-primary".to_string()],
+                    ..Default::default()
+                },
+            ),
+        ]);
+
+        let dependencies = hive.dependencies().unwrap();
+
+        assert_eq!(dependencies[&Name("db-primary".into())], HashSet::new());
+        assert_eq!(
+            dependencies[&Name("db-replica".into())],
+            HashSet::from([Name("db-primary".into())])
+        );
+        assert_eq!(
+            dependencies[&Name("app".into())],
+            HashSet::from([Name("db-primary".into()), Name("db-replica".into())])
+        );
+    }
+
+    #[test]
+    fn dependencies_unknown() {
+        let hive = hive_of(vec![(
+            "app",
+            Node {
+                depends_on: vec!["db".to_string()],
+                ..Default::default()
+            },
+        )]);
+
+        assert_matches!(
+            hive.dependencies(),
+            Err(HiveLibError::HiveInitializationError(
+                HiveInitializationError::UnknownDependency { .. }
+            ))
+        );
+    }
+
+    #[test]
+    fn dependencies_cycle() {
+        let hive = hive_of(vec![
+            (
+                "a",
+                Node {
+                    depends_on: vec!["b".to_string()],
+                    ..Default::default()
+                },
+            ),
+            (
+                "b",
+                Node {
+                    depends_on: vec!["a".to_string()],
+                    ..Default::default()
+                },
+            ),
+            ("c", Node::default()),
+        ]);
+
+        assert_matches!(
+            hive.dependencies(),
+            Err(HiveLibError::HiveInitializationError(
+                HiveInitializationError::DependencyCycle(names)
+            )) if names == vec!["a".to_string(), "b".to_string()]
+        );
+    }
+
     // flake should always come before hive.nix
     #[test]
     fn test_hive_dot_nix_priority() {
@@ -278,6 +471,8 @@ mod tests {
                 group: "root".into(),
                 user: "root".into(),
                 permissions: "0600".into(),
+                dest_dir_permissions: "0755".into(),
+                strict_ownership: true,
                 source: Source::String("hi".into()),
                 upload_at: UploadKeyAt::PreActivation,
                 environment: im::HashMap::new()
@@ -5,31 +5,41 @@
 use enum_dispatch::enum_dispatch;
 use gethostname::gethostname;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::assert_matches::debug_assert_matches;
 use std::env;
-use std::fmt::Display;
+use std::fmt::{Display, Write};
 use std::io::ErrorKind;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::sync::oneshot;
-use tracing::{Instrument, Level, Span, debug, error, event, instrument, trace};
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, Level, Span, debug, error, event, instrument, trace, warn};
 
 use crate::commands::common::evaluate_hive_attribute;
-use crate::commands::{CommandArguments, WireCommandChip, run_command};
+use crate::commands::{CommandArguments, PrivilegeEscalation, WireCommandChip, run_command};
 use crate::errors::{CommandError, NetworkError};
 use crate::hive::HiveLocation;
+use crate::hive::facts::Liveness;
+use crate::hive::journal::{Journal, JournalEntry};
+use crate::hive::policy::{Retries, StepKind, StepPolicy, StepPolicyOverrides, Timeouts};
 use crate::hive::steps::build::Build;
 use crate::hive::steps::cleanup::{CleanUp, clean_up_control_master};
+use crate::hive::steps::diff::{Diff, DiffMode};
 use crate::hive::steps::evaluate::Evaluate;
 use crate::hive::steps::keys::{Key, Keys, PushKeyAgent, UploadKeyAt};
 use crate::hive::steps::ping::Ping;
 use crate::hive::steps::push::{PushBuildOutput, PushEvaluatedOutput};
+use crate::hive::steps::rollback::Rollback;
 use crate::{EvalGoal, StrictHostKeyChecking, SubCommandModifiers};
 
 use super::HiveLibError;
-use super::steps::activate::SwitchToConfiguration;
+use super::steps::activate::{MagicRollback, SwitchToConfiguration};
 
-#[derive(Serialize, Deserialize, Clone, Debug, Hash, Eq, PartialEq, derive_more::Display)]
+#[derive(
+    Serialize, Deserialize, Clone, Debug, Hash, Eq, PartialEq, Ord, PartialOrd, derive_more::Display,
+)]
 pub struct Name(pub Arc<str>);
 
 #[derive(Serialize, Deserialize, Clone, Debug, Hash, Eq, PartialEq)]
@@ -38,6 +48,25 @@ pub struct Target {
     pub user: Arc<str>,
     pub port: u32,
 
+    #[serde(rename = "identityFile")]
+    pub identity_file: Option<PathBuf>,
+
+    /// Bastions to connect through, in order
+    #[serde(rename = "proxyJump")]
+    pub proxy_jump: Vec<Arc<str>>,
+
+    /// Extra `-o` options, such as `ServerAliveInterval=10`
+    #[serde(rename = "sshOptions")]
+    pub ssh_options: Vec<String>,
+
+    #[serde(rename = "knownHostsFile")]
+    pub known_hosts_file: Option<PathBuf>,
+
+    /// Public keys the hosts must present. When set, no other host key is
+    /// accepted.
+    #[serde(rename = "hostKeys")]
+    pub host_keys: Vec<String>,
+
     #[serde(skip)]
     current_host: usize,
 }
@@ -66,10 +95,27 @@ impl Target {
             "-p".to_string(),
             self.port.to_string(),
         ];
+
+        if let Some(identity_file) = &self.identity_file {
+            vector.extend(["-i".to_string(), identity_file.display().to_string()]);
+        }
+
+        if !self.proxy_jump.is_empty() {
+            vector.extend(["-J".to_string(), self.proxy_jump.join(",")]);
+        }
+
+        // pinned keys are always checked, even with `--ssh-accept-host`
+        let strict_host_key_checking = if self.host_keys.is_empty() {
+            modifiers.ssh_accept_host
+        } else {
+            StrictHostKeyChecking::Yes
+        };
+
         let mut options = vec![
             format!(
                 "StrictHostKeyChecking={}",
-                match modifiers.ssh_accept_host {
+                match strict_host_key_checking {
+                    StrictHostKeyChecking::Yes => "yes",
                     StrictHostKeyChecking::AcceptNew => "accept-new",
                     StrictHostKeyChecking::No => "no",
                 }
@@ -89,26 +135,96 @@ impl Target {
             "ControlPersist=yes".to_string(),
         ]);
 
+        if !self.host_keys.is_empty() {
+            let known_hosts_file = self
+                .write_known_hosts()
+                .map_err(HiveLibError::CommandError)?;
+            options.push(format!("UserKnownHostsFile={}", known_hosts_file.display()));
+        } else if let Some(known_hosts_file) = &self.known_hosts_file {
+            options.push(format!("UserKnownHostsFile={}", known_hosts_file.display()));
+        }
+
+        // ssh uses the first value given for each option, so these cannot
+        // override the options wire relies on
+        options.extend(self.ssh_options.iter().cloned());
+
         vector.push("-o".to_string());
         vector.extend(options.into_iter().intersperse("-o".to_string()));
 
         Ok(vector)
     }
+
+    /// `known_hosts` lines pinning `host_keys` to every host of the target
+    fn known_hosts(&self) -> String {
+        let hosts = self
+            .hosts
+            .iter()
+            .map(|host| {
+                if self.port == 22 {
+                    host.to_string()
+                } else {
+                    format!("[{host}]:{}", self.port)
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+
+        self.host_keys
+            .iter()
+            .fold(String::new(), |mut known_hosts, key| {
+                let _ = writeln!(known_hosts, "{hosts} {}", key.trim());
+                known_hosts
+            })
+    }
+
+    /// Writes the pinned host keys to a file in `$XDG_RUNTIME_DIR/wire`
+    /// named after its contents, so that it is only written once per run
+    fn write_known_hosts(&self) -> Result<PathBuf, CommandError> {
+        let contents = self.known_hosts();
+        let digest = Sha256::digest(&contents);
+        let name = digest
+            .iter()
+            .take(16)
+            .fold(String::new(), |mut name, byte| {
+                let _ = write!(name, "{byte:02x}");
+                name
+            });
+
+        let path = get_runtime_directory()?.join(format!("known_hosts-{name}"));
+
+        if path.exists() {
+            return Ok(path);
+        }
+
+        // ssh may be reading the file of a concurrent connection
+        let temporary = path.with_extension(std::process::id().to_string());
+        std::fs::write(&temporary, contents).map_err(CommandError::KnownHosts)?;
+
+        match std::fs::rename(&temporary, &path) {
+            Err(_) if path.exists() => Ok(path),
+            Err(err) => Err(CommandError::KnownHosts(err)),
+            Ok(()) => Ok(path),
+        }
+    }
 }
 
 fn get_control_path() -> Result<String, CommandError> {
+    get_runtime_directory().map(|directory| directory.join("%C").display().to_string())
+}
+
+fn get_runtime_directory() -> Result<PathBuf, CommandError> {
     match env::var("XDG_RUNTIME_DIR") {
         Ok(runtime_dir) => {
-            let control_path = PathBuf::from(runtime_dir).join("wire");
+            let directory = PathBuf::from(runtime_dir).join("wire");
 
-            match std::fs::create_dir(&control_path) {
+            match std::fs::create_dir(&directory) {
                 Err(err) if err.kind() != ErrorKind::AlreadyExists => {
                     return Err(CommandError::RuntimeDirectory(err));
                 }
                 _ => (),
             }
 
-            Ok(control_path.join("%C").display().to_string())
+            Ok(directory)
         }
         Err(err) => Err(CommandError::RuntimeDirectoryMissing(err)),
     }
@@ -121,6 +237,11 @@ impl Default for Target {
             hosts: vec!["NAME".into()],
             user: "root".into(),
             port: 22,
+            identity_file: None,
+            proxy_jump: Vec::new(),
+            ssh_options: Vec::new(),
+            known_hosts_file: None,
+            host_keys: Vec::new(),
             current_host: 0,
         }
     }
@@ -139,10 +260,13 @@ impl<'a> Context<'a> {
             hive_location: Arc::new(hive_location),
             modifiers: SubCommandModifiers::default(),
             no_keys: false,
+            force_keys: false,
+            no_prune_keys: false,
             state: StepState::default(),
             goal: Goal::SwitchToConfiguration(SwitchToConfigurationGoal::Switch),
             reboot: false,
             should_apply_locally: false,
+            diff: DiffMode::default(),
         }
     }
 }
@@ -158,6 +282,24 @@ impl Target {
         self.current_host += 1;
     }
 
+    /// Creates an `ssh` command logging into the preferred host, which runs
+    /// `command` if it is not empty and opens a login shell otherwise
+    pub fn create_interactive_ssh_command(
+        &self,
+        modifiers: SubCommandModifiers,
+        command: &[String],
+    ) -> Result<tokio::process::Command, HiveLibError> {
+        let mut ssh = tokio::process::Command::new("ssh");
+        ssh.args(self.create_ssh_args(modifiers, false, false)?);
+        ssh.arg(self.get_preferred_host()?.to_string());
+
+        if !command.is_empty() {
+            ssh.arg("--").args(command);
+        }
+
+        Ok(ssh)
+    }
+
     #[cfg(test)]
     #[must_use]
     pub fn from_host(host: &str) -> Self {
@@ -193,6 +335,21 @@ pub struct Node {
     #[serde(rename = "allowLocalDeployment")]
     pub allow_local_deployment: bool,
 
+    #[serde(rename = "magicRollback")]
+    pub magic_rollback: MagicRollback,
+
+    #[serde(rename = "dependsOn")]
+    pub depends_on: Vec<String>,
+
+    #[serde(rename = "timeouts")]
+    pub timeouts: Timeouts,
+
+    #[serde(rename = "retries")]
+    pub retries: Retries,
+
+    #[serde(rename = "privilegeEscalation")]
+    pub privilege_escalation: PrivilegeEscalation,
+
     #[serde(default)]
     pub tags: im::HashSet<String>,
 
@@ -201,6 +358,10 @@ pub struct Node {
 
     #[serde(rename(deserialize = "_hostPlatform", serialize = "host_platform"))]
     pub host_platform: Arc<str>,
+
+    /// Only populated by `Hive::gather_facts`
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub liveness: Option<Liveness>,
 }
 
 #[cfg(test)]
@@ -212,7 +373,13 @@ impl Default for Node {
             tags: im::HashSet::new(),
             allow_local_deployment: true,
             build_remotely: false,
+            magic_rollback: MagicRollback::default(),
+            depends_on: Vec::new(),
+            timeouts: Timeouts::default(),
+            retries: Retries::default(),
+            privilege_escalation: PrivilegeEscalation::default(),
             host_platform: "x86_64-linux".into(),
+            liveness: None,
         }
     }
 }
@@ -233,14 +400,12 @@ impl Node {
 
         let host = self.target.get_preferred_host()?;
 
-        let command_string = format!(
-            "ssh {}@{host} {} -N",
-            self.target.user,
-            self.target.create_ssh_opts(modifiers, true)?
-        );
+        let mut argv = vec!["ssh".to_string(), format!("{}@{host}", self.target.user)];
+        argv.extend(self.target.create_ssh_args(modifiers, false, true)?);
+        argv.push("-N".to_string());
 
         let output = run_command(
-            &CommandArguments::new(command_string, modifiers)
+            &CommandArguments::from_argv(argv, modifiers)
                 .log_stdout()
                 .mode(crate::commands::ChildOutputMode::Interactive),
         )?;
@@ -254,6 +419,37 @@ impl Node {
 
         Ok(())
     }
+
+    /// Pings each of the node's hosts in order until one answers, returning
+    /// the host that did
+    pub async fn ping_with_failover(
+        &mut self,
+        modifiers: SubCommandModifiers,
+    ) -> Result<Arc<str>, HiveLibError> {
+        self.target.current_host = 0;
+
+        loop {
+            event!(
+                Level::INFO,
+                status = "attempting",
+                host = self.target.get_preferred_host()?.to_string()
+            );
+
+            if self.ping(modifiers).await.is_ok() {
+                let host = self.target.get_preferred_host()?.clone();
+                event!(Level::INFO, status = "success", host = host.to_string());
+                return Ok(host);
+            }
+
+            // ? will take us out if we ran out of hosts
+            event!(
+                Level::WARN,
+                status = "failed to ping",
+                host = self.target.get_preferred_host()?.to_string()
+            );
+            self.target.host_failed();
+        }
+    }
 }
 
 #[must_use]
@@ -267,7 +463,7 @@ pub enum Push<'a> {
     Path(&'a String),
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
 pub struct Derivation(String);
 
 impl Display for Derivation {
@@ -276,7 +472,8 @@ impl Display for Derivation {
     }
 }
 
-#[derive(derive_more::Display, Debug, Clone, Copy)]
+#[derive(derive_more::Display, Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
 pub enum SwitchToConfigurationGoal {
     Switch,
     Boot,
@@ -284,12 +481,16 @@ pub enum SwitchToConfigurationGoal {
     DryActivate,
 }
 
-#[derive(derive_more::Display, Clone, Copy)]
+#[derive(derive_more::Display, Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
 pub enum Goal {
     SwitchToConfiguration(SwitchToConfigurationGoal),
     Build,
     Push,
     Keys,
+    /// Switch to a previous generation, or the given generation number
+    #[display("Rollback")]
+    Rollback(Option<u32>),
 }
 
 #[enum_dispatch]
@@ -305,18 +506,27 @@ pub struct StepState {
     pub evaluation_rx: Option<oneshot::Receiver<Result<Derivation, HiveLibError>>>,
     pub build: Option<String>,
     pub key_agent_directory: Option<String>,
+    /// Progress recorded for this node by a previous apply that did not
+    /// finish
+    pub resumed: Option<JournalEntry>,
 }
 
+#[allow(clippy::struct_excessive_bools)]
 pub struct Context<'a> {
     pub name: &'a Name,
     pub node: &'a mut Node,
     pub hive_location: Arc<HiveLocation>,
     pub modifiers: SubCommandModifiers,
     pub no_keys: bool,
+    /// Upload every key, even those already in place on the node
+    pub force_keys: bool,
+    /// Keep keys on the node that are no longer declared
+    pub no_prune_keys: bool,
     pub state: StepState,
     pub goal: Goal,
     pub reboot: bool,
     pub should_apply_locally: bool,
+    pub diff: DiffMode,
 }
 
 #[enum_dispatch(ExecuteStep)]
@@ -329,7 +539,9 @@ enum Step {
     PushEvaluatedOutput,
     Build,
     PushBuildOutput,
+    Diff,
     SwitchToConfiguration,
+    Rollback,
     CleanUp,
 }
 
@@ -343,15 +555,94 @@ impl Display for Step {
             Self::PushEvaluatedOutput(step) => step.fmt(f),
             Self::Build(step) => step.fmt(f),
             Self::PushBuildOutput(step) => step.fmt(f),
+            Self::Diff(step) => step.fmt(f),
             Self::SwitchToConfiguration(step) => step.fmt(f),
+            Self::Rollback(step) => step.fmt(f),
             Self::CleanUp(step) => step.fmt(f),
         }
     }
 }
 
+impl Step {
+    /// The timeout and retries that apply to this step, if any
+    const fn kind(&self) -> Option<StepKind> {
+        match self {
+            Self::Ping(..) => Some(StepKind::Ping),
+            Self::Keys(..) => Some(StepKind::Keys),
+            Self::PushKeyAgent(..) | Self::PushEvaluatedOutput(..) | Self::PushBuildOutput(..) => {
+                Some(StepKind::Push)
+            }
+            Self::Build(..) => Some(StepKind::Build),
+            Self::SwitchToConfiguration(..) | Self::Rollback(..) => Some(StepKind::Activate),
+            Self::Evaluate(..) | Self::Diff(..) | Self::CleanUp(..) => None,
+        }
+    }
+
+    /// Whether cancellation may drop the step midway. Steps that change the
+    /// node always run to completion, so a cancelled node is never left
+    /// halfway through uploading keys or activating.
+    const fn interruptible(&self) -> bool {
+        !matches!(
+            self,
+            Self::Keys(..)
+                | Self::SwitchToConfiguration(..)
+                | Self::Rollback(..)
+                | Self::CleanUp(..)
+        )
+    }
+}
+
+/// Runs `attempt` of the step named `step`, giving up once the timeout of
+/// `policy` elapses and retrying with backoff as often as `policy` allows. A
+/// timed out attempt is dropped, which kills the commands it ran, before the
+/// next attempt starts.
+async fn with_policy(
+    policy: StepPolicy,
+    name: &Name,
+    step: &str,
+    mut attempt: impl AsyncFnMut() -> Result<(), HiveLibError>,
+) -> Result<(), HiveLibError> {
+    let mut retry = 0;
+
+    loop {
+        let result = match (policy.timeout, policy.kind) {
+            (Some(timeout), Some(kind)) => tokio::time::timeout(timeout, attempt())
+                .await
+                .unwrap_or_else(|_| {
+                    Err(HiveLibError::StepTimedOut {
+                        name: name.clone(),
+                        step: step.to_string(),
+                        kind,
+                        timeout: timeout.as_secs(),
+                    })
+                }),
+            _ => attempt().await,
+        };
+
+        match result {
+            Err(err) if retry < policy.retries => {
+                retry += 1;
+                let backoff = StepPolicy::backoff(retry);
+
+                warn!(
+                    "`{step}` failed, retrying in {}s ({retry}/{}): {err}",
+                    backoff.as_secs(),
+                    policy.retries
+                );
+
+                tokio::time::sleep(backoff).await;
+            }
+            result => return result,
+        }
+    }
+}
+
 pub struct GoalExecutor<'a> {
     steps: Vec<Step>,
     context: Context<'a>,
+    cancellation: CancellationToken,
+    overrides: StepPolicyOverrides,
+    journal: Option<Arc<Journal>>,
 }
 
 impl<'a> GoalExecutor<'a> {
@@ -371,16 +662,68 @@ impl<'a> GoalExecutor<'a> {
                 Step::PushEvaluatedOutput(super::steps::push::PushEvaluatedOutput),
                 Step::Build(super::steps::build::Build),
                 Step::PushBuildOutput(super::steps::push::PushBuildOutput),
+                Step::Diff(Diff),
                 Step::SwitchToConfiguration(SwitchToConfiguration),
                 Step::Keys(Keys {
                     filter: UploadKeyAt::PostActivation,
                 }),
+                Step::Rollback(Rollback),
                 Step::CleanUp(CleanUp),
             ],
             context,
+            cancellation: CancellationToken::new(),
+            overrides: StepPolicyOverrides::default(),
+            journal: None,
         }
     }
 
+    /// Stops the execution, after cleaning up, once `token` is cancelled
+    #[must_use]
+    pub fn cancelled_by(mut self, token: CancellationToken) -> Self {
+        self.cancellation = token;
+        self
+    }
+
+    /// Records each completed step in `journal`, and resumes from what a
+    /// previous apply recorded there
+    #[must_use]
+    pub fn journaled(mut self, journal: Arc<Journal>) -> Self {
+        self.journal = Some(journal);
+        self
+    }
+
+    /// Uses the given timeouts and retries instead of those of the node
+    #[must_use]
+    pub fn with_overrides(mut self, overrides: StepPolicyOverrides) -> Self {
+        self.overrides = overrides;
+        self
+    }
+
+    /// Runs `step`, giving up once its timeout elapses and retrying it with
+    /// backoff as often as `policy` allows
+    async fn execute_step(
+        step: &Step,
+        context: &mut Context<'_>,
+        policy: StepPolicy,
+    ) -> Result<(), HiveLibError> {
+        let name = context.name.clone();
+
+        with_policy(policy, &name, &step.to_string(), async || {
+            step.execute(context).await
+        })
+        .await
+    }
+
+    /// Names of the steps `execute` would run, in order
+    #[must_use]
+    pub fn planned_steps(&self) -> Vec<String> {
+        self.steps
+            .iter()
+            .filter(|step| step.should_execute(&self.context))
+            .map(ToString::to_string)
+            .collect()
+    }
+
     #[instrument(skip_all, name = "eval")]
     async fn evaluate_task(
         tx: oneshot::Sender<Result<Derivation, HiveLibError>>,
@@ -405,6 +748,11 @@ impl<'a> GoalExecutor<'a> {
         let (tx, rx) = oneshot::channel();
         self.context.state.evaluation_rx = Some(rx);
 
+        if let Some(journal) = &self.journal {
+            self.context.state.resumed = journal.entry(self.context.name);
+            journal.record_started(self.context.name);
+        }
+
         // The name of this span should never be changed without updating
         // `wire/cli/tracing_setup.rs`
         debug_assert_matches!(Span::current().metadata().unwrap().name(), "execute");
@@ -418,7 +766,10 @@ impl<'a> GoalExecutor<'a> {
                 .is_some()
         );
 
-        if !matches!(self.context.goal, Goal::Keys) {
+        // a plan already provides the evaluated toplevel
+        let evaluation = (!matches!(self.context.goal, Goal::Keys | Goal::Rollback(..))
+            && self.context.state.evaluation.is_none())
+        .then(|| {
             tokio::spawn(
                 GoalExecutor::evaluate_task(
                     tx,
@@ -427,8 +778,8 @@ impl<'a> GoalExecutor<'a> {
                     self.context.modifiers,
                 )
                 .in_current_span(),
-            );
-        }
+            )
+        });
 
         let steps = self
             .steps
@@ -447,14 +798,55 @@ impl<'a> GoalExecutor<'a> {
                 progress = format!("{}/{length}", position + 1)
             );
 
-            if let Err(err) = step.execute(&mut self.context).await.inspect_err(|_| {
-                error!("Failed to execute `{step}`");
-            }) {
+            let started = Instant::now();
+            let policy = StepPolicy::resolve(step.kind(), self.context.node, &self.overrides);
+
+            let result = if self.cancellation.is_cancelled() {
+                error!("Cancelled before `{step}`");
+                Err(HiveLibError::Cancelled(self.context.name.clone()))
+            } else if step.interruptible() {
+                tokio::select! {
+                    result = Self::execute_step(step, &mut self.context, policy) => result.inspect_err(|_| {
+                        error!("Failed to execute `{step}`");
+                    }),
+                    () = self.cancellation.cancelled() => {
+                        error!("Cancelled during `{step}`");
+                        Err(HiveLibError::Cancelled(self.context.name.clone()))
+                    }
+                }
+            } else {
+                Self::execute_step(step, &mut self.context, policy)
+                    .await
+                    .inspect_err(|_| {
+                        error!("Failed to execute `{step}`");
+                    })
+            };
+
+            if let Err(err) = result {
+                if let Some(evaluation) = &evaluation {
+                    evaluation.abort();
+                }
+
                 // discard error from cleanup
                 let _ = CleanUp.execute(&mut self.context).await;
 
                 return Err(err);
             }
+
+            if let Some(journal) = &self.journal {
+                journal.record_step(self.context.name, step.to_string(), &self.context.state);
+            }
+
+            // `wire/cli/events.rs` relies on the `elapsed_ms` field
+            event!(
+                Level::DEBUG,
+                step = step.to_string(),
+                elapsed_ms = u64::try_from(started.elapsed().as_millis()).unwrap_or(u64::MAX)
+            );
+        }
+
+        if let Some(journal) = &self.journal {
+            journal.record_finished(self.context.name);
         }
 
         Ok(())
@@ -472,7 +864,7 @@ mod tests {
         location,
     };
     use std::{assert_matches::assert_matches, path::PathBuf};
-    use std::{collections::HashMap, env};
+    use std::{collections::HashMap, env, ffi::OsStr, sync::LazyLock};
 
     fn get_steps(goal_executor: GoalExecutor) -> std::vec::Vec<Step> {
         goal_executor
@@ -614,6 +1006,56 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn order_rollback_only() {
+        let location = location!(get_test_path!());
+        let mut node = Node::default();
+        let name = &Name(function_name!().into());
+        let mut context = Context::create_test_context(location, name, &mut node);
+
+        context.goal = Goal::Rollback(None);
+
+        let executor = GoalExecutor::new(context);
+        let steps = get_steps(executor);
+
+        assert_eq!(steps, vec![Ping.into(), Rollback.into(), CleanUp.into()]);
+    }
+
+    #[tokio::test]
+    async fn order_diff() {
+        let location = location!(get_test_path!());
+        let mut node = Node::default();
+        let name = &Name(function_name!().into());
+        let mut context = Context::create_test_context(location, name, &mut node);
+
+        context.diff = DiffMode::Confirm;
+
+        let executor = GoalExecutor::new(context);
+        let steps = get_steps(executor);
+
+        assert_eq!(
+            steps,
+            vec![
+                Ping.into(),
+                PushKeyAgent.into(),
+                Keys {
+                    filter: UploadKeyAt::PreActivation
+                }
+                .into(),
+                crate::hive::steps::evaluate::Evaluate.into(),
+                crate::hive::steps::build::Build.into(),
+                crate::hive::steps::push::PushBuildOutput.into(),
+                Diff.into(),
+                SwitchToConfiguration.into(),
+                Keys {
+                    filter: UploadKeyAt::PostActivation
+                }
+                .into(),
+                CleanUp.into()
+            ]
+        );
+    }
+
     #[tokio::test]
     async fn order_remote_build() {
         let location = location!(get_test_path!());
@@ -648,6 +1090,77 @@ mod tests {
         );
     }
 
+    #[tokio::test(start_paused = true)]
+    async fn timed_out_attempts_end_before_retrying() {
+        /// Marks the attempt as finished once dropped, even when timed out
+        struct Running<'a>(&'a std::cell::Cell<bool>);
+
+        impl Drop for Running<'_> {
+            fn drop(&mut self) {
+                self.0.set(false);
+            }
+        }
+
+        let name = Name("node-1".into());
+        let running = std::cell::Cell::new(false);
+        let attempts = std::cell::Cell::new(0);
+        let policy = StepPolicy {
+            kind: Some(StepKind::Keys),
+            timeout: Some(std::time::Duration::from_secs(5)),
+            retries: 2,
+        };
+
+        let result = with_policy(policy, &name, "Upload keys", async || {
+            assert!(!running.replace(true), "attempts overlapped");
+            let _running = Running(&running);
+            attempts.set(attempts.get() + 1);
+
+            if attempts.get() < 3 {
+                tokio::time::sleep(std::time::Duration::from_mins(1)).await;
+            }
+
+            Ok(())
+        })
+        .await;
+
+        assert_matches!(result, Ok(()));
+        assert_eq!(attempts.get(), 3);
+
+        // gives up once out of retries
+        attempts.set(0);
+        let result = with_policy(policy, &name, "Upload keys", async || {
+            attempts.set(attempts.get() + 1);
+            tokio::time::sleep(std::time::Duration::from_mins(1)).await;
+            Ok(())
+        })
+        .await;
+
+        assert_matches!(
+            result,
+            Err(HiveLibError::StepTimedOut {
+                kind: StepKind::Keys,
+                timeout: 5,
+                ..
+            })
+        );
+        assert_eq!(attempts.get(), 3);
+    }
+
+    #[test]
+    fn steps_changing_the_node_are_not_interruptible() {
+        assert!(Step::from(Ping).interruptible());
+        assert!(Step::from(Build).interruptible());
+        assert!(Step::from(PushBuildOutput).interruptible());
+        assert!(
+            !Step::from(Keys {
+                filter: UploadKeyAt::PreActivation
+            })
+            .interruptible()
+        );
+        assert!(!Step::from(SwitchToConfiguration).interruptible());
+        assert!(!Step::from(CleanUp).interruptible());
+    }
+
     #[test]
     fn target_fails_increments() {
         let mut target = Target::from_host("localhost");
@@ -694,13 +1207,9 @@ mod tests {
         }
     }
 
-    #[test]
-    fn test_ssh_opts() {
-        let target = Target::from_host("hello-world");
-        let subcommand_modifiers = SubCommandModifiers {
-            non_interactive: false,
-            ..Default::default()
-        };
+    /// Shared by every test creating ssh arguments, as they read
+    /// `XDG_RUNTIME_DIR` concurrently
+    static RUNTIME_DIR: LazyLock<String> = LazyLock::new(|| {
         let tmp = format!(
             "/tmp/{}",
             rand::distr::SampleString::sample_string(&Alphabetic, &mut rand::rng(), 10)
@@ -710,6 +1219,18 @@ mod tests {
 
         unsafe { env::set_var("XDG_RUNTIME_DIR", &tmp) }
 
+        tmp
+    });
+
+    #[test]
+    fn test_ssh_opts() {
+        let target = Target::from_host("hello-world");
+        let subcommand_modifiers = SubCommandModifiers {
+            non_interactive: false,
+            ..Default::default()
+        };
+        let tmp = &*RUNTIME_DIR;
+
         let args = [
             "-l".to_string(),
             target.user.to_string(),
@@ -780,6 +1301,15 @@ mod tests {
             ]
         );
 
+        let ssh = target.create_interactive_ssh_command(subcommand_modifiers, &["ls".into()]);
+        let expected = [&args[..], &["hello-world".into(), "--".into(), "ls".into()]].concat();
+        assert!(
+            ssh.unwrap()
+                .as_std()
+                .get_args()
+                .eq(expected.iter().map(OsStr::new))
+        );
+
         // forced non interactive is the same as --non-interactive
         assert_eq!(
             target
@@ -797,4 +1327,95 @@ mod tests {
                 .unwrap()
         );
     }
+
+    #[test]
+    fn test_ssh_opts_target_options() {
+        let tmp = &*RUNTIME_DIR;
+        let target = Target {
+            identity_file: Some("/home/user/.ssh/deploy".into()),
+            proxy_jump: vec!["bastion-1".into(), "admin@bastion-2:2222".into()],
+            ssh_options: vec!["ServerAliveInterval=10".to_string()],
+            known_hosts_file: Some("/etc/wire/known_hosts".into()),
+            ..Target::from_host("hello-world")
+        };
+
+        assert_eq!(
+            target
+                .create_ssh_args(SubCommandModifiers::default(), true, false)
+                .unwrap(),
+            [
+                "-l",
+                "root",
+                "-p",
+                "22",
+                "-i",
+                "/home/user/.ssh/deploy",
+                "-J",
+                "bastion-1,admin@bastion-2:2222",
+                "-o",
+                "StrictHostKeyChecking=accept-new",
+                "-o",
+                "PasswordAuthentication=no",
+                "-o",
+                "KbdInteractiveAuthentication=no",
+                "-o",
+                "ControlMaster=no",
+                "-o",
+                &format!("ControlPath={tmp}/wire/%C"),
+                "-o",
+                "ControlPersist=yes",
+                "-o",
+                "UserKnownHostsFile=/etc/wire/known_hosts",
+                "-o",
+                "ServerAliveInterval=10",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ssh_opts_host_keys() {
+        let tmp = &*RUNTIME_DIR;
+        let target = Target {
+            hosts: vec!["node-1".into(), "10.0.0.1".into()],
+            port: 2222,
+            host_keys: vec!["ssh-ed25519 AAAA".to_string(), "ssh-rsa BBBB\n".to_string()],
+            known_hosts_file: Some("/etc/wire/known_hosts".into()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            target.known_hosts(),
+            "[node-1]:2222,[10.0.0.1]:2222 ssh-ed25519 AAAA\n\
+            [node-1]:2222,[10.0.0.1]:2222 ssh-rsa BBBB\n"
+        );
+
+        let args = target
+            .create_ssh_args(SubCommandModifiers::default(), false, false)
+            .unwrap();
+        let known_hosts_file = args
+            .iter()
+            .find_map(|arg| arg.strip_prefix("UserKnownHostsFile="))
+            .unwrap();
+
+        assert!(args.contains(&"StrictHostKeyChecking=yes".to_string()));
+        assert!(known_hosts_file.starts_with(&format!("{tmp}/wire/known_hosts-")));
+        assert_eq!(
+            std::fs::read_to_string(known_hosts_file).unwrap(),
+            target.known_hosts()
+        );
+
+        // --ssh-accept-host does not disable checking pinned keys
+        let args = target
+            .create_ssh_args(
+                SubCommandModifiers {
+                    ssh_accept_host: StrictHostKeyChecking::No,
+                    ..Default::default()
+                },
+                false,
+                false,
+            )
+            .unwrap();
+        assert!(args.contains(&"StrictHostKeyChecking=yes".to_string()));
+        assert!(!args.contains(&"StrictHostKeyChecking=no".to_string()));
+    }
 }
@@ -0,0 +1,435 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+// Copyright 2024-2025 wire Contributors
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::instrument;
+
+use crate::{
+    EvalGoal, HiveLibError, SubCommandModifiers,
+    commands::{
+        ChildOutputMode, CommandArguments, Either, WireCommandChip,
+        common::evaluate_hive_attribute, run_command,
+    },
+    errors::{HiveInitializationError, PlanError},
+    hive::{
+        HiveLocation,
+        node::{Context, Derivation, ExecuteStep, Goal, GoalExecutor, Name, Node, Target},
+        steps::{
+            build::Build,
+            cleanup::CleanUp,
+            keys::{Key, key_digest},
+            ping::Ping,
+            push::PushEvaluatedOutput,
+        },
+    },
+};
+
+/// Bumped whenever a field is removed or changes meaning.
+pub const PLAN_VERSION: u32 = 1;
+
+/// Directories of version control systems, which are not part of a hive's
+/// source
+const VCS_DIRECTORIES: [&str; 4] = [".git", ".hg", ".jj", ".svn"];
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct PlannedKey {
+    pub name: String,
+    pub destination: String,
+    pub digest: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct PlannedNode {
+    pub target: Target,
+    /// Empty for goals that do not evaluate the node
+    pub derivation: Option<Derivation>,
+    /// Empty for goals that do not build the node
+    pub out_path: Option<String>,
+    pub keys: Vec<PlannedKey>,
+    /// Steps `wire apply --plan` will run, in order
+    pub steps: Vec<String>,
+}
+
+/// The outcome of `wire plan`, which `wire apply --plan` executes without
+/// evaluating the hive's toplevels again
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct Plan {
+    pub version: u32,
+    /// Identifies the hive's source and lock file the plan was made from
+    pub fingerprint: String,
+    pub goal: Goal,
+    pub no_keys: bool,
+    pub nodes: BTreeMap<Name, PlannedNode>,
+}
+
+impl Plan {
+    pub fn read(path: &Path) -> Result<Self, HiveLibError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|err| HiveLibError::PlanError(PlanError::Io(err)))?;
+
+        let plan = serde_json::from_str::<Plan>(&contents)
+            .map_err(|err| HiveLibError::PlanError(PlanError::Parse(err)))?;
+
+        if plan.version != PLAN_VERSION {
+            return Err(HiveLibError::PlanError(PlanError::Version(plan.version)));
+        }
+
+        Ok(plan)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), HiveLibError> {
+        let contents = serde_json::to_string_pretty(self)
+            .map_err(|err| HiveLibError::PlanError(PlanError::Parse(err)))?;
+
+        std::fs::write(path, contents).map_err(|err| HiveLibError::PlanError(PlanError::Io(err)))
+    }
+
+    /// Refuses the plan stored at `path` if the hive's source or lock file
+    /// changed since it was made, or if a node's keys were added, removed or
+    /// have different contents.
+    pub async fn verify(
+        &self,
+        path: &Path,
+        location: &HiveLocation,
+        nodes: &std::collections::HashMap<Name, Node>,
+        modifiers: SubCommandModifiers,
+    ) -> Result<(), HiveLibError> {
+        let current = fingerprint(location, path, modifiers).await?;
+
+        if current != self.fingerprint {
+            return Err(HiveLibError::PlanError(PlanError::Stale {
+                planned: self.fingerprint.clone(),
+                current,
+            }));
+        }
+
+        for (name, planned) in &self.nodes {
+            let node = nodes.get(name).ok_or_else(|| {
+                HiveLibError::PlanError(PlanError::NodeDoesNotExist(name.clone()))
+            })?;
+
+            if !plans_keys(self.goal, self.no_keys) {
+                continue;
+            }
+
+            let keys = key_digests(node.keys.iter())
+                .await
+                .map_err(|(key, err)| HiveLibError::KeyError(key, err))?;
+
+            if let Some(key) = changed_key(&planned.keys, &keys) {
+                return Err(HiveLibError::PlanError(PlanError::KeyChanged {
+                    name: name.clone(),
+                    key: key.to_string(),
+                }));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Hashes the hive's source, which for flakes includes `flake.lock`. The plan
+/// at `plan` is not part of the source, neither are VCS directories.
+pub async fn fingerprint(
+    location: &HiveLocation,
+    plan: &Path,
+    modifiers: SubCommandModifiers,
+) -> Result<String, HiveLibError> {
+    let uri = match location {
+        HiveLocation::Flake(uri) => uri,
+        HiveLocation::HiveNix(path) => {
+            return hash_directory(path.parent().unwrap_or(path), plan)
+                .map_err(|err| HiveLibError::PlanError(PlanError::Io(err)));
+        }
+    };
+
+    let child = run_command(
+        &CommandArguments::from_argv(
+            [
+                "nix",
+                "--extra-experimental-features",
+                "nix-command",
+                "--extra-experimental-features",
+                "flakes",
+                "flake",
+                "metadata",
+                "--json",
+                uri,
+            ],
+            modifiers,
+        )
+        .mode(ChildOutputMode::Nix),
+    )?;
+
+    let stdout = match child
+        .wait_till_success()
+        .await
+        .map_err(HiveLibError::CommandError)?
+    {
+        Either::Left((_, stdout)) | Either::Right((_, stdout)) => stdout,
+    };
+
+    let metadata = gjson::parse(&stdout);
+    let digest = Sha256::digest(format!(
+        "{}\n{}",
+        metadata.get("path").str(),
+        metadata.get("locks").json()
+    ));
+
+    Ok(format!("sha256-{}", BASE64_STANDARD.encode(digest)))
+}
+
+/// Hashes the names, kinds and contents of every entry below `root`, except
+/// for `exclude` and VCS directories.
+fn hash_directory(root: &Path, exclude: &Path) -> std::io::Result<String> {
+    let root = std::path::absolute(root)?;
+    let exclude = std::path::absolute(exclude)?;
+    let mut hasher = Sha256::new();
+    let mut pending = vec![PathBuf::new()];
+
+    while let Some(relative) = pending.pop() {
+        let mut entries = std::fs::read_dir(root.join(&relative))?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        entries.sort();
+
+        for entry in entries {
+            let relative = relative.join(&entry);
+            let path = root.join(&relative);
+
+            if path == exclude
+                || VCS_DIRECTORIES
+                    .iter()
+                    .any(|directory| entry.as_os_str() == *directory)
+            {
+                continue;
+            }
+
+            let file_type = std::fs::symlink_metadata(&path)?.file_type();
+            hasher.update(relative.as_os_str().as_encoded_bytes());
+
+            if file_type.is_dir() {
+                hasher.update(b"\0directory\0");
+                pending.push(relative);
+            } else if file_type.is_symlink() {
+                hasher.update(b"\0symlink\0");
+                hasher.update(std::fs::read_link(&path)?.as_os_str().as_encoded_bytes());
+            } else {
+                let contents = std::fs::read(&path)?;
+                hasher.update(b"\0file\0");
+                hasher.update(contents.len().to_le_bytes());
+                hasher.update(contents);
+            }
+
+            hasher.update(b"\0");
+        }
+    }
+
+    Ok(format!(
+        "sha256-{}",
+        BASE64_STANDARD.encode(hasher.finalize())
+    ))
+}
+
+/// Whether a plan for `goal` lists the keys of its nodes
+const fn plans_keys(goal: Goal, no_keys: bool) -> bool {
+    !no_keys && matches!(goal, Goal::Keys | Goal::SwitchToConfiguration(..))
+}
+
+/// Returns the name of a key that was added, removed or changed since
+/// `planned` was made.
+fn changed_key<'a>(planned: &'a [PlannedKey], current: &'a [PlannedKey]) -> Option<&'a str> {
+    planned
+        .iter()
+        .find(|key| !current.contains(key))
+        .or_else(|| current.iter().find(|key| !planned.contains(key)))
+        .map(|key| key.name.as_str())
+}
+
+async fn key_digests(
+    keys: impl Iterator<Item = &Key>,
+) -> Result<Vec<PlannedKey>, (String, crate::errors::KeyError)> {
+    let mut digests = Vec::new();
+
+    for key in keys {
+        digests.push(PlannedKey {
+            name: key.name.clone(),
+            destination: Path::new(&key.dest_dir)
+                .join(&key.name)
+                .display()
+                .to_string(),
+            digest: key_digest(key)
+                .await
+                .map_err(|err| (key.name.clone(), err))?,
+        });
+    }
+
+    Ok(digests)
+}
+
+/// Evaluates and builds the node of `context`, and lists what applying
+/// `context.goal` to it would do.
+#[instrument(skip_all, name = "plan", fields(node = %context.name))]
+pub async fn plan_node(mut context: Context<'_>) -> Result<PlannedNode, HiveLibError> {
+    let goal = context.goal;
+
+    if !matches!(goal, Goal::Keys | Goal::Rollback(..)) {
+        let output = evaluate_hive_attribute(
+            &context.hive_location,
+            &EvalGoal::GetTopLevel(context.name),
+            context.modifiers,
+        )
+        .await?;
+
+        context.state.evaluation = Some(serde_json::from_str(&output).map_err(|err| {
+            HiveLibError::HiveInitializationError(HiveInitializationError::ParseEvaluateError(err))
+        })?);
+    }
+
+    if matches!(goal, Goal::Build | Goal::SwitchToConfiguration(..)) {
+        let remote = context.node.build_remotely && !context.should_apply_locally;
+
+        let result = async {
+            if remote {
+                Ping.execute(&mut context).await?;
+                PushEvaluatedOutput.execute(&mut context).await?;
+            }
+
+            Build.execute(&mut context).await
+        }
+        .await;
+
+        if remote {
+            let _ = CleanUp.execute(&mut context).await;
+        }
+
+        result?;
+    }
+
+    let keys = if plans_keys(goal, context.no_keys) {
+        key_digests(context.node.keys.iter())
+            .await
+            .map_err(|(key, err)| HiveLibError::KeyError(key, err))?
+    } else {
+        Vec::new()
+    };
+
+    Ok(PlannedNode {
+        target: context.node.target.clone(),
+        derivation: context.state.evaluation.clone(),
+        out_path: context
+            .state
+            .build
+            .as_ref()
+            .map(|path| path.trim().to_string()),
+        keys,
+        steps: GoalExecutor::new(context).planned_steps(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hive::node::SwitchToConfigurationGoal;
+
+    #[test]
+    fn round_trip() {
+        let tmp = tempdir::TempDir::new("wire-plan").unwrap();
+        let path = tmp.path().join("plan.json");
+
+        let plan = Plan {
+            version: PLAN_VERSION,
+            fingerprint: "sha256-AAAA".to_string(),
+            goal: Goal::SwitchToConfiguration(SwitchToConfigurationGoal::Switch),
+            no_keys: false,
+            nodes: BTreeMap::from([(
+                Name("node-1".into()),
+                PlannedNode {
+                    target: Target::from_host("node-1"),
+                    derivation: None,
+                    out_path: Some("/nix/store/aaaa-nixos-system-node-1".to_string()),
+                    keys: vec![PlannedKey {
+                        name: "secret".to_string(),
+                        destination: "/run/keys/secret".to_string(),
+                        digest: "sha256-BBBB".to_string(),
+                    }],
+                    steps: vec!["Ping node".to_string()],
+                },
+            )]),
+        };
+
+        plan.write(&path).unwrap();
+        assert_eq!(Plan::read(&path).unwrap(), plan);
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(contents.contains(r#""switch-to-configuration": "switch""#));
+
+        std::fs::write(
+            &path,
+            contents.replace(r#""version": 1"#, r#""version": 99"#),
+        )
+        .unwrap();
+        assert!(matches!(
+            Plan::read(&path),
+            Err(HiveLibError::PlanError(PlanError::Version(99)))
+        ));
+    }
+
+    fn planned_key(name: &str, digest: &str) -> PlannedKey {
+        PlannedKey {
+            name: name.to_string(),
+            destination: format!("/run/keys/{name}"),
+            digest: digest.to_string(),
+        }
+    }
+
+    #[test]
+    fn changed_keys() {
+        let planned = vec![planned_key("a", "sha256-AAAA")];
+
+        assert_eq!(changed_key(&planned, &planned), None);
+        assert_eq!(
+            changed_key(&planned, &[planned_key("a", "sha256-BBBB")]),
+            Some("a")
+        );
+        assert_eq!(changed_key(&planned, &[]), Some("a"));
+        assert_eq!(
+            changed_key(
+                &planned,
+                &[
+                    planned_key("a", "sha256-AAAA"),
+                    planned_key("b", "sha256-BBBB")
+                ]
+            ),
+            Some("b")
+        );
+    }
+
+    #[test]
+    fn hash_directory_skips_plan_and_vcs() {
+        let tmp = tempdir::TempDir::new("wire-plan").unwrap();
+        let plan = tmp.path().join("plan.json");
+        std::fs::write(tmp.path().join("hive.nix"), "{ }").unwrap();
+        std::fs::create_dir(tmp.path().join(".git")).unwrap();
+
+        let before = hash_directory(tmp.path(), &plan).unwrap();
+
+        std::fs::write(&plan, "{}").unwrap();
+        std::fs::write(tmp.path().join(".git").join("index"), "changed").unwrap();
+        assert_eq!(hash_directory(tmp.path(), &plan).unwrap(), before);
+
+        std::fs::create_dir(tmp.path().join("modules")).unwrap();
+        std::fs::write(tmp.path().join("modules").join("a.nix"), "{ }").unwrap();
+        let after = hash_directory(tmp.path(), &plan).unwrap();
+        assert_ne!(after, before);
+
+        std::fs::write(tmp.path().join("modules").join("a.nix"), "{ a = 1; }").unwrap();
+        assert_ne!(hash_directory(tmp.path(), &plan).unwrap(), after);
+    }
+}
@@ -0,0 +1,239 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+// Copyright 2024-2025 wire Contributors
+
+use std::{collections::HashMap, fmt::Display, str::FromStr, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::hive::node::Node;
+
+/// Longest wait between two attempts of a step
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Groups of steps that share a timeout and retry count
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum StepKind {
+    Ping,
+    Keys,
+    Push,
+    Build,
+    Activate,
+}
+
+impl StepKind {
+    /// Whether steps of this kind are safe to run again after failing
+    #[must_use]
+    pub const fn is_idempotent(self) -> bool {
+        matches!(self, StepKind::Ping | StepKind::Keys | StepKind::Push)
+    }
+}
+
+impl Display for StepKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StepKind::Ping => write!(f, "ping"),
+            StepKind::Keys => write!(f, "keys"),
+            StepKind::Push => write!(f, "push"),
+            StepKind::Build => write!(f, "build"),
+            StepKind::Activate => write!(f, "activate"),
+        }
+    }
+}
+
+impl FromStr for StepKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ping" => Ok(StepKind::Ping),
+            "keys" => Ok(StepKind::Keys),
+            "push" => Ok(StepKind::Push),
+            "build" => Ok(StepKind::Build),
+            "activate" => Ok(StepKind::Activate),
+            other => Err(format!(
+                "unknown step kind `{other}`, expected one of ping, keys, push, build, activate"
+            )),
+        }
+    }
+}
+
+/// `deployment.timeouts`, in seconds. Steps without a timeout may run
+/// indefinitely.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct Timeouts {
+    pub ping: Option<u64>,
+    pub keys: Option<u64>,
+    pub push: Option<u64>,
+    pub build: Option<u64>,
+    pub activate: Option<u64>,
+}
+
+impl Timeouts {
+    #[must_use]
+    pub const fn get(&self, kind: StepKind) -> Option<u64> {
+        match kind {
+            StepKind::Ping => self.ping,
+            StepKind::Keys => self.keys,
+            StepKind::Push => self.push,
+            StepKind::Build => self.build,
+            StepKind::Activate => self.activate,
+        }
+    }
+}
+
+/// `deployment.retries`, the number of times a failed step is attempted
+/// again. Only idempotent steps are retried.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct Retries {
+    pub ping: u32,
+    pub keys: u32,
+    pub push: u32,
+}
+
+impl Retries {
+    #[must_use]
+    pub const fn get(&self, kind: StepKind) -> u32 {
+        match kind {
+            StepKind::Ping => self.ping,
+            StepKind::Keys => self.keys,
+            StepKind::Push => self.push,
+            StepKind::Build | StepKind::Activate => 0,
+        }
+    }
+}
+
+/// Timeouts and retries passed on the command line, which take precedence
+/// over those of each node
+#[derive(Clone, Debug, Default)]
+pub struct StepPolicyOverrides {
+    pub timeouts: HashMap<StepKind, u64>,
+    pub retries: HashMap<StepKind, u32>,
+}
+
+/// How a single step of a node is executed
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct StepPolicy {
+    pub kind: Option<StepKind>,
+    pub timeout: Option<Duration>,
+    pub retries: u32,
+}
+
+impl StepPolicy {
+    #[must_use]
+    pub fn resolve(kind: Option<StepKind>, node: &Node, overrides: &StepPolicyOverrides) -> Self {
+        let Some(kind) = kind else {
+            return StepPolicy::default();
+        };
+
+        let timeout = overrides
+            .timeouts
+            .get(&kind)
+            .copied()
+            .or(node.timeouts.get(kind))
+            .map(Duration::from_secs);
+
+        let retries = if kind.is_idempotent() {
+            overrides
+                .retries
+                .get(&kind)
+                .copied()
+                .unwrap_or(node.retries.get(kind))
+        } else {
+            0
+        };
+
+        StepPolicy {
+            kind: Some(kind),
+            timeout,
+            retries,
+        }
+    }
+
+    /// Time to wait before the given attempt, starting at one second for the
+    /// first retry and doubling up to 30 seconds
+    #[must_use]
+    pub fn backoff(retry: u32) -> Duration {
+        Duration::from_secs(
+            1u64.checked_shl(retry.saturating_sub(1))
+                .unwrap_or(u64::MAX),
+        )
+        .min(MAX_BACKOFF)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve() {
+        let node = Node {
+            timeouts: Timeouts {
+                ping: Some(10),
+                build: Some(3600),
+                ..Default::default()
+            },
+            retries: Retries {
+                ping: 3,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let overrides = StepPolicyOverrides {
+            timeouts: HashMap::from([(StepKind::Build, 90)]),
+            retries: HashMap::from([(StepKind::Push, 2), (StepKind::Activate, 5)]),
+        };
+
+        assert_eq!(
+            StepPolicy::resolve(Some(StepKind::Ping), &node, &overrides),
+            StepPolicy {
+                kind: Some(StepKind::Ping),
+                timeout: Some(Duration::from_secs(10)),
+                retries: 3,
+            }
+        );
+
+        assert_eq!(
+            StepPolicy::resolve(Some(StepKind::Build), &node, &overrides).timeout,
+            Some(Duration::from_secs(90))
+        );
+
+        assert_eq!(
+            StepPolicy::resolve(Some(StepKind::Push), &node, &overrides),
+            StepPolicy {
+                kind: Some(StepKind::Push),
+                timeout: None,
+                retries: 2,
+            }
+        );
+
+        // activation is never retried
+        assert_eq!(
+            StepPolicy::resolve(Some(StepKind::Activate), &node, &overrides).retries,
+            0
+        );
+
+        assert_eq!(
+            StepPolicy::resolve(None, &node, &overrides),
+            StepPolicy::default()
+        );
+    }
+
+    #[test]
+    fn backoff() {
+        assert_eq!(StepPolicy::backoff(1), Duration::from_secs(1));
+        assert_eq!(StepPolicy::backoff(2), Duration::from_secs(2));
+        assert_eq!(StepPolicy::backoff(4), Duration::from_secs(8));
+        assert_eq!(StepPolicy::backoff(6), MAX_BACKOFF);
+        assert_eq!(StepPolicy::backoff(100), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn parse_kind() {
+        assert_eq!("push".parse(), Ok(StepKind::Push));
+        assert_eq!(StepKind::Activate.to_string(), "activate");
+        assert!("evaluate".parse::<StepKind>().is_err());
+    }
+}
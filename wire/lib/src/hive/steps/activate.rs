@@ -3,15 +3,39 @@
 
 use std::fmt::Display;
 
+use serde::{Deserialize, Serialize};
 use tracing::{error, info, instrument, warn};
 
 use crate::{
     HiveLibError,
-    commands::{CommandArguments, WireCommandChip, run_command},
+    commands::{CommandArguments, Either, WireCommandChip, run_command, shell_join, shell_quote},
     errors::{ActivationError, NetworkError},
     hive::node::{Context, ExecuteStep, Goal, SwitchToConfigurationGoal},
 };
 
+/// Prefix of the transient systemd units that roll the node back unless wire
+/// confirms the activation in time.
+const WATCHDOG_UNIT: &str = "wire-magic-rollback";
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct MagicRollback {
+    pub enable: bool,
+    pub timeout: u64,
+    #[serde(rename = "healthChecks")]
+    pub health_checks: Vec<String>,
+}
+
+#[cfg(test)]
+impl Default for MagicRollback {
+    fn default() -> Self {
+        MagicRollback {
+            enable: false,
+            timeout: 120,
+            health_checks: Vec::new(),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct SwitchToConfiguration;
 
@@ -42,22 +66,28 @@ async fn wait_for_ping(ctx: &Context<'_>) -> Result<(), HiveLibError> {
 
 async fn set_profile(
     goal: SwitchToConfigurationGoal,
-    built_path: &String,
+    built_path: &str,
     ctx: &Context<'_>,
 ) -> Result<(), HiveLibError> {
     info!("Setting profiles in anticipation for switch-to-configuration {goal}");
 
-    let command_string = format!("nix-env -p /nix/var/nix/profiles/system/ --set {built_path}");
+    let argv = [
+        "nix-env",
+        "-p",
+        "/nix/var/nix/profiles/system/",
+        "--set",
+        built_path.trim(),
+    ];
 
     let child = run_command(
-        &CommandArguments::new(command_string, ctx.modifiers)
+        &CommandArguments::from_argv(argv, ctx.modifiers)
             .mode(crate::commands::ChildOutputMode::Nix)
             .on_target(if ctx.should_apply_locally {
                 None
             } else {
                 Some(&ctx.node.target)
             })
-            .elevated(),
+            .elevated(ctx.node),
     )?;
 
     let _ = child
@@ -70,6 +100,289 @@ async fn set_profile(
     Ok(())
 }
 
+const fn should_magic_rollback(goal: SwitchToConfigurationGoal, ctx: &Context<'_>) -> bool {
+    ctx.node.magic_rollback.enable
+        && !ctx.should_apply_locally
+        && matches!(
+            goal,
+            SwitchToConfigurationGoal::Switch | SwitchToConfigurationGoal::Test
+        )
+}
+
+/// Returns the command that brings the node back to `previous`, a system
+/// that was active before this activation.
+fn create_rollback_command(goal: SwitchToConfigurationGoal, previous: &str) -> String {
+    let switch_to_configuration = format!("{previous}/bin/switch-to-configuration");
+
+    match goal {
+        SwitchToConfigurationGoal::Switch => format!(
+            "{} && {}",
+            shell_join([
+                "nix-env",
+                "-p",
+                "/nix/var/nix/profiles/system",
+                "--set",
+                previous
+            ]),
+            shell_join([&switch_to_configuration, "switch"])
+        ),
+        _ => shell_join([&switch_to_configuration, "test"]),
+    }
+}
+
+/// The watchdog of a single activation. Each activation has its own unit and
+/// confirmation file, so a watchdog never acts on another activation.
+struct Watchdog {
+    unit: String,
+    /// File the watchdog checks for before rolling back
+    confirmation: String,
+    rollback_command: String,
+}
+
+impl Watchdog {
+    fn new(id: &str, rollback_command: String) -> Self {
+        Watchdog {
+            unit: format!("{WATCHDOG_UNIT}-{id}.service"),
+            confirmation: format!("/run/{WATCHDOG_UNIT}-{id}-confirmed"),
+            rollback_command,
+        }
+    }
+
+    /// The watchdog runs as a transient systemd unit so it outlives wire's
+    /// SSH connection.
+    fn arm_argv(&self, timeout: u64) -> Vec<String> {
+        vec![
+            "systemd-run".to_string(),
+            format!("--unit={}", self.unit),
+            "--collect".to_string(),
+            "sh".to_string(),
+            "-c".to_string(),
+            format!(
+                "sleep {timeout}; test -e {} || ({})",
+                shell_quote(&self.confirmation),
+                self.rollback_command
+            ),
+        ]
+    }
+
+    /// Only confirms while the watchdog is still waiting, otherwise it has
+    /// already rolled the node back. The watchdog is stopped once confirmed.
+    fn confirm_command(&self) -> String {
+        format!(
+            "{} && {} && {} && {}",
+            shell_join(["systemctl", "is-active", "--quiet", &self.unit]),
+            shell_join(["touch", &self.confirmation]),
+            shell_join(["systemctl", "stop", &self.unit]),
+            shell_join(["rm", "-f", &self.confirmation]),
+        )
+    }
+
+    /// Disarms the watchdog and rolls back immediately
+    fn trigger_command(&self) -> String {
+        format!(
+            "{} && {}",
+            shell_join(["systemctl", "stop", &self.unit]),
+            self.rollback_command
+        )
+    }
+}
+
+/// Fails if a watchdog of a previous activation is still waiting to be
+/// confirmed, as it would roll back this activation.
+async fn ensure_no_watchdog(ctx: &Context<'_>) -> Result<(), HiveLibError> {
+    let child = run_command(
+        &CommandArguments::from_argv(
+            [
+                "systemctl",
+                "list-units",
+                "--plain",
+                "--no-legend",
+                "--state=active",
+                &format!("{WATCHDOG_UNIT}-*.service"),
+            ],
+            ctx.modifiers,
+        )
+        .on_target(Some(&ctx.node.target)),
+    )?;
+
+    let armed = match child
+        .wait_till_success()
+        .await
+        .map_err(HiveLibError::CommandError)?
+    {
+        Either::Left((_, stdout)) | Either::Right((_, stdout)) => stdout,
+    };
+
+    if let Some(unit) = armed.split_whitespace().next() {
+        return Err(HiveLibError::ActivationError(
+            ActivationError::WatchdogArmed(ctx.name.clone(), unit.to_string()),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Arms the magic rollback watchdog of this activation.
+async fn arm_watchdog(
+    goal: SwitchToConfigurationGoal,
+    ctx: &Context<'_>,
+) -> Result<Watchdog, HiveLibError> {
+    ensure_no_watchdog(ctx).await?;
+
+    // `switch` rolls back the profile, `test` never touches it.
+    let link = match goal {
+        SwitchToConfigurationGoal::Switch => "/nix/var/nix/profiles/system",
+        _ => "/run/current-system",
+    };
+
+    let child = run_command(
+        &CommandArguments::from_argv(["readlink", "-f", link], ctx.modifiers)
+            .on_target(Some(&ctx.node.target)),
+    )?;
+
+    let previous = match child
+        .wait_till_success()
+        .await
+        .map_err(HiveLibError::CommandError)?
+    {
+        Either::Left((_, stdout)) | Either::Right((_, stdout)) => stdout.trim().to_string(),
+    };
+
+    let watchdog = Watchdog::new(
+        &format!("{:08x}", rand::random::<u32>()),
+        create_rollback_command(goal, &previous),
+    );
+
+    info!(
+        "Arming magic rollback to {previous}, activation must be confirmed within {}s",
+        ctx.node.magic_rollback.timeout
+    );
+
+    let child = run_command(
+        &CommandArguments::from_argv(
+            watchdog.arm_argv(ctx.node.magic_rollback.timeout),
+            ctx.modifiers,
+        )
+        .on_target(Some(&ctx.node.target))
+        .elevated(ctx.node),
+    )?;
+
+    let _ = child
+        .wait_till_success()
+        .await
+        .map_err(HiveLibError::CommandError)?;
+
+    Ok(watchdog)
+}
+
+/// Disarms the watchdog and rolls back immediately instead of waiting for the
+/// timeout to elapse.
+async fn trigger_rollback(watchdog: &Watchdog, ctx: &Context<'_>) -> Result<(), HiveLibError> {
+    warn!("Rolling back {name}", name = ctx.name);
+
+    let child = run_command(
+        &CommandArguments::new(watchdog.trigger_command(), ctx.modifiers)
+            .on_target(Some(&ctx.node.target))
+            .elevated(ctx.node)
+            .log_stdout(),
+    )?;
+
+    let _ = child
+        .wait_till_success()
+        .await
+        .map_err(HiveLibError::CommandError)?;
+
+    info!("Rolled back {name}", name = ctx.name);
+
+    Ok(())
+}
+
+/// Confirms the activation over a fresh connection, after every health check
+/// passes. The watchdog will roll back the node if this fails.
+async fn confirm_activation(watchdog: &Watchdog, ctx: &Context<'_>) -> Result<(), HiveLibError> {
+    // `ping` tears down the existing ControlMaster, so this proves the node
+    // still accepts new connections.
+    if wait_for_ping(ctx).await.is_err() {
+        return Err(HiveLibError::ActivationError(ActivationError::Unconfirmed(
+            ctx.name.clone(),
+        )));
+    }
+
+    for check in &ctx.node.magic_rollback.health_checks {
+        info!("Running health check `{check}`");
+
+        let child = run_command(
+            &CommandArguments::new(check, ctx.modifiers)
+                .on_target(Some(&ctx.node.target))
+                .log_stdout(),
+        )?;
+
+        if let Err(source) = child.wait_till_success().await {
+            error!("Health check `{check}` failed on {name}", name = ctx.name);
+
+            trigger_rollback(watchdog, ctx).await?;
+
+            return Err(HiveLibError::ActivationError(
+                ActivationError::HealthCheckFailed {
+                    name: ctx.name.clone(),
+                    check: check.clone(),
+                    source: Box::new(source),
+                },
+            ));
+        }
+    }
+
+    let child = run_command(
+        &CommandArguments::new(watchdog.confirm_command(), ctx.modifiers)
+            .on_target(Some(&ctx.node.target))
+            .elevated(ctx.node),
+    )?;
+
+    if child.wait_till_success().await.is_err() {
+        return Err(HiveLibError::ActivationError(ActivationError::Unconfirmed(
+            ctx.name.clone(),
+        )));
+    }
+
+    info!("Confirmed activation of {name}", name = ctx.name);
+
+    Ok(())
+}
+
+async fn reboot(ctx: &Context<'_>) -> Result<(), HiveLibError> {
+    warn!("Rebooting {name}!", name = ctx.name);
+
+    let reboot = run_command(
+        &CommandArguments::from_argv(["reboot", "now"], ctx.modifiers)
+            .log_stdout()
+            .on_target(Some(&ctx.node.target))
+            .elevated(ctx.node),
+    )?;
+
+    // consume result, impossible to know if the machine failed to reboot or we
+    // simply disconnected
+    let _ = reboot
+        .wait_till_success()
+        .await
+        .map_err(HiveLibError::CommandError)?;
+
+    info!("Rebooted {name}, waiting to reconnect...", name = ctx.name);
+
+    if wait_for_ping(ctx).await.is_ok() {
+        return Ok(());
+    }
+
+    error!(
+        "Failed to get regain connection to {name} via {host} after reboot.",
+        name = ctx.name,
+        host = ctx.node.target.get_preferred_host()?
+    );
+
+    Err(HiveLibError::NetworkError(
+        NetworkError::HostUnreachableAfterReboot(ctx.node.target.get_preferred_host()?.to_string()),
+    ))
+}
+
 impl ExecuteStep for SwitchToConfiguration {
     fn should_execute(&self, ctx: &Context) -> bool {
         matches!(ctx.goal, Goal::SwitchToConfiguration(..))
@@ -83,6 +396,14 @@ impl ExecuteStep for SwitchToConfiguration {
             unreachable!("Cannot reach as guarded by should_execute")
         };
 
+        // must be armed before the profile is set, so the previous generation
+        // can be recorded
+        let watchdog = if should_magic_rollback(*goal, ctx) {
+            Some(arm_watchdog(*goal, ctx).await?)
+        } else {
+            None
+        };
+
         if matches!(
             goal,
             // switch profile if switch or boot
@@ -94,24 +415,25 @@ impl ExecuteStep for SwitchToConfiguration {
 
         info!("Running switch-to-configuration {goal}");
 
-        let command_string = format!(
-            "{built_path}/bin/switch-to-configuration {}",
+        let argv = [
+            format!("{}/bin/switch-to-configuration", built_path.trim()),
             match goal {
                 SwitchToConfigurationGoal::Switch => "switch",
                 SwitchToConfigurationGoal::Boot => "boot",
                 SwitchToConfigurationGoal::Test => "test",
                 SwitchToConfigurationGoal::DryActivate => "dry-activate",
             }
-        );
+            .to_string(),
+        ];
 
         let child = run_command(
-            &CommandArguments::new(command_string, ctx.modifiers)
+            &CommandArguments::from_argv(argv, ctx.modifiers)
                 .on_target(if ctx.should_apply_locally {
                     None
                 } else {
                     Some(&ctx.node.target)
                 })
-                .elevated()
+                .elevated(ctx.node)
                 .log_stdout(),
         )?;
 
@@ -119,6 +441,10 @@ impl ExecuteStep for SwitchToConfiguration {
 
         match result {
             Ok(_) => {
+                if let Some(watchdog) = &watchdog {
+                    confirm_activation(watchdog, ctx).await?;
+                }
+
                 if !ctx.reboot {
                     return Ok(());
                 }
@@ -129,39 +455,7 @@ impl ExecuteStep for SwitchToConfiguration {
                     return Ok(());
                 }
 
-                warn!("Rebooting {name}!", name = ctx.name);
-
-                let reboot = run_command(
-                    &CommandArguments::new("reboot now", ctx.modifiers)
-                        .log_stdout()
-                        .on_target(Some(&ctx.node.target))
-                        .elevated(),
-                )?;
-
-                // consume result, impossible to know if the machine failed to reboot or we
-                // simply disconnected
-                let _ = reboot
-                    .wait_till_success()
-                    .await
-                    .map_err(HiveLibError::CommandError)?;
-
-                info!("Rebooted {name}, waiting to reconnect...", name = ctx.name);
-
-                if wait_for_ping(ctx).await.is_ok() {
-                    return Ok(());
-                }
-
-                error!(
-                    "Failed to get regain connection to {name} via {host} after reboot.",
-                    name = ctx.name,
-                    host = ctx.node.target.get_preferred_host()?
-                );
-
-                return Err(HiveLibError::NetworkError(
-                    NetworkError::HostUnreachableAfterReboot(
-                        ctx.node.target.get_preferred_host()?.to_string(),
-                    ),
-                ));
+                reboot(ctx).await
             }
             Err(error) => {
                 warn!(
@@ -180,6 +474,10 @@ impl ExecuteStep for SwitchToConfiguration {
                 }
 
                 if wait_for_ping(ctx).await.is_ok() {
+                    if let Some(watchdog) = &watchdog {
+                        trigger_rollback(watchdog, ctx).await?;
+                    }
+
                     return Err(HiveLibError::ActivationError(
                         ActivationError::SwitchToConfigurationError(*goal, ctx.name.clone(), error),
                     ));
@@ -191,6 +489,12 @@ impl ExecuteStep for SwitchToConfiguration {
                     host = ctx.node.target.get_preferred_host()?
                 );
 
+                if watchdog.is_some() {
+                    return Err(HiveLibError::ActivationError(ActivationError::Unconfirmed(
+                        ctx.name.clone(),
+                    )));
+                }
+
                 return Err(HiveLibError::NetworkError(
                     NetworkError::HostUnreachableAfterReboot(
                         ctx.node.target.get_preferred_host()?.to_string(),
@@ -200,3 +504,62 @@ impl ExecuteStep for SwitchToConfiguration {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rollback_command_per_goal() {
+        assert_eq!(
+            create_rollback_command(
+                SwitchToConfigurationGoal::Switch,
+                "/nix/store/aaa-nixos-system"
+            ),
+            "nix-env -p /nix/var/nix/profiles/system --set /nix/store/aaa-nixos-system && \
+            /nix/store/aaa-nixos-system/bin/switch-to-configuration switch"
+        );
+
+        assert_eq!(
+            create_rollback_command(
+                SwitchToConfigurationGoal::Test,
+                "/nix/store/aaa-nixos-system"
+            ),
+            "/nix/store/aaa-nixos-system/bin/switch-to-configuration test"
+        );
+
+        // `previous` is read from the node, so it must never run as shell
+        assert_eq!(
+            create_rollback_command(
+                SwitchToConfigurationGoal::Switch,
+                "/nix/store/aaa-$(reboot)'"
+            ),
+            "nix-env -p /nix/var/nix/profiles/system --set '/nix/store/aaa-$(reboot)'\\''' && \
+            '/nix/store/aaa-$(reboot)'\\''/bin/switch-to-configuration' switch"
+        );
+    }
+
+    #[test]
+    fn watchdog_commands() {
+        let watchdog = Watchdog::new("0000abcd", "rollback".to_string());
+
+        assert_eq!(
+            shell_join(watchdog.arm_argv(30)),
+            "systemd-run --unit=wire-magic-rollback-0000abcd.service --collect sh -c \
+            'sleep 30; test -e /run/wire-magic-rollback-0000abcd-confirmed || (rollback)'"
+        );
+
+        assert_eq!(
+            watchdog.confirm_command(),
+            "systemctl is-active --quiet wire-magic-rollback-0000abcd.service && \
+            touch /run/wire-magic-rollback-0000abcd-confirmed && \
+            systemctl stop wire-magic-rollback-0000abcd.service && \
+            rm -f /run/wire-magic-rollback-0000abcd-confirmed"
+        );
+
+        assert_eq!(
+            watchdog.trigger_command(),
+            "systemctl stop wire-magic-rollback-0000abcd.service && rollback"
+        );
+    }
+}
@@ -2,13 +2,17 @@
 // Copyright 2024-2025 wire Contributors
 
 use std::fmt::Display;
+use std::path::Path;
 
 use tracing::{info, instrument};
 
 use crate::{
     HiveLibError,
     commands::{CommandArguments, Either, WireCommandChip, run_command_with_env},
-    hive::node::{Context, ExecuteStep, Goal},
+    hive::{
+        journal::JournalEntry,
+        node::{Context, ExecuteStep, Goal},
+    },
 };
 
 #[derive(Debug, PartialEq)]
@@ -22,20 +26,40 @@ impl Display for Build {
 
 impl ExecuteStep for Build {
     fn should_execute(&self, ctx: &Context) -> bool {
-        !matches!(ctx.goal, Goal::Keys | Goal::Push)
+        !matches!(ctx.goal, Goal::Keys | Goal::Push | Goal::Rollback(..))
     }
 
     #[instrument(skip_all, name = "build")]
     async fn execute(&self, ctx: &mut Context<'_>) -> Result<(), HiveLibError> {
         let top_level = ctx.state.evaluation.as_ref().unwrap();
 
-        let command_string = format!(
-            "nix --extra-experimental-features nix-command \
-            build --print-build-logs --no-link --print-out-paths {top_level}"
-        );
+        if let Some(JournalEntry {
+            derivation: Some(derivation),
+            build: Some(build),
+            ..
+        }) = &ctx.state.resumed
+            && *derivation == top_level.to_string()
+            && (ctx.node.build_remotely || Path::new(build.trim()).exists())
+        {
+            info!("Reusing output built by the previous apply: {build:?}");
+            ctx.state.build = Some(build.clone());
+
+            return Ok(());
+        }
+
+        let argv = [
+            "nix",
+            "--extra-experimental-features",
+            "nix-command",
+            "build",
+            "--print-build-logs",
+            "--no-link",
+            "--print-out-paths",
+            &top_level.to_string(),
+        ];
 
         let status = run_command_with_env(
-            &CommandArguments::new(command_string, ctx.modifiers)
+            &CommandArguments::from_argv(argv, ctx.modifiers)
                 .on_target(if ctx.node.build_remotely {
                     Some(&ctx.node.target)
                 } else {
@@ -0,0 +1,316 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+// Copyright 2024-2025 wire Contributors
+
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs::OpenOptions,
+    io::{BufRead, BufReader, Write},
+};
+
+use tracing::{info, instrument, warn};
+
+use crate::{
+    HiveLibError, STDIN_CLOBBER_LOCK, SubCommandModifiers,
+    commands::{ChildOutputMode, CommandArguments, Either, WireCommandChip, run_command},
+    errors::ActivationError,
+    hive::node::{Context, ExecuteStep, Goal, Name, Target},
+};
+
+#[derive(Debug, PartialEq)]
+pub struct Diff;
+
+/// Whether to show the closure diff of a node before activating it
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum DiffMode {
+    #[default]
+    Disabled,
+    Show,
+    /// Show the diff and ask before activating
+    Confirm,
+}
+
+impl Display for Diff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Diff the closure against the current system")
+    }
+}
+
+/// Store paths that were added, removed, or changed version between two
+/// closures, as reported by `nix store diff-closures`.
+#[derive(Debug, Default, PartialEq)]
+struct ClosureDiff {
+    added: Vec<String>,
+    removed: Vec<String>,
+    changed: Vec<String>,
+}
+
+impl ClosureDiff {
+    /// Parses lines such as `linux: 6.6.1 → 6.12.3, +120.0 MiB`. A version of
+    /// `∅` marks a package that is missing from one side of the diff. Lines
+    /// that only report a size change are not collected.
+    fn parse(output: &str) -> Self {
+        let mut diff = ClosureDiff::default();
+
+        for line in output.lines() {
+            let line = String::from_utf8_lossy(&strip_ansi_escapes::strip(line)).to_string();
+            let Some((_, versions)) = line.trim().split_once(": ") else {
+                continue;
+            };
+            let versions = versions.split(", ").next().unwrap_or_default();
+            let Some((old, new)) = versions.split_once(" → ") else {
+                continue;
+            };
+
+            let bucket = match (old, new) {
+                ("∅", _) => &mut diff.added,
+                (_, "∅") => &mut diff.removed,
+                _ => &mut diff.changed,
+            };
+
+            bucket.push(line.trim().to_string());
+        }
+
+        diff
+    }
+}
+
+impl Display for ClosureDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} added, {} removed, {} changed",
+            self.added.len(),
+            self.removed.len(),
+            self.changed.len()
+        )
+    }
+}
+
+/// Parses the output of `nix path-info --closure-size`, mapping each store
+/// path to its closure size in bytes.
+fn parse_closure_sizes(output: &str) -> HashMap<&str, u64> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let path = fields.next()?;
+            let size = fields.next_back()?.parse().ok()?;
+
+            Some((path, size))
+        })
+        .collect()
+}
+
+fn format_size_delta(delta: i128) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let sign = if delta < 0 { '-' } else { '+' };
+    #[allow(clippy::cast_precision_loss)]
+    let mut size = delta.unsigned_abs() as f64;
+    let mut unit = 0;
+
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{sign}{size:.1} {}", UNITS[unit])
+}
+
+async fn query(
+    argv: &[&str],
+    mode: ChildOutputMode,
+    target: Option<&Target>,
+    modifiers: SubCommandModifiers,
+) -> Result<String, HiveLibError> {
+    let child = run_command(
+        &CommandArguments::from_argv(argv, modifiers)
+            .on_target(target)
+            .mode(mode),
+    )?;
+
+    match child
+        .wait_till_success()
+        .await
+        .map_err(HiveLibError::CommandError)?
+    {
+        Either::Left((_, stdout)) | Either::Right((_, stdout)) => Ok(stdout),
+    }
+}
+
+/// Produces a summary such as `2 added, 0 removed, 14 changed, +120.0 MiB`,
+/// logging every version change along the way.
+async fn summarise(ctx: &Context<'_>, built: &str) -> Result<String, HiveLibError> {
+    let target = if ctx.should_apply_locally {
+        None
+    } else {
+        Some(&ctx.node.target)
+    };
+
+    let current = query(
+        &["readlink", "-f", "/run/current-system"],
+        ChildOutputMode::Generic,
+        target,
+        ctx.modifiers,
+    )
+    .await?;
+    let current = current.trim();
+
+    let diff = ClosureDiff::parse(
+        &query(
+            &[
+                "nix",
+                "--extra-experimental-features",
+                "nix-command",
+                "store",
+                "diff-closures",
+                current,
+                built,
+            ],
+            ChildOutputMode::Nix,
+            target,
+            ctx.modifiers,
+        )
+        .await?,
+    );
+
+    for line in diff.added.iter().chain(&diff.removed).chain(&diff.changed) {
+        info!("{line}");
+    }
+
+    let sizes = query(
+        &[
+            "nix",
+            "--extra-experimental-features",
+            "nix-command",
+            "path-info",
+            "--closure-size",
+            current,
+            built,
+        ],
+        ChildOutputMode::Nix,
+        target,
+        ctx.modifiers,
+    )
+    .await?;
+    let sizes = parse_closure_sizes(&sizes);
+
+    Ok(match (sizes.get(current), sizes.get(built)) {
+        (Some(old), Some(new)) => format!(
+            "{diff}, {}",
+            format_size_delta(i128::from(*new) - i128::from(*old))
+        ),
+        _ => diff.to_string(),
+    })
+}
+
+/// Asks on the controlling terminal, so that it works even when stdin was
+/// consumed by `--on -`.
+fn prompt(name: &Name, summary: &str) -> Result<bool, std::io::Error> {
+    let _guard = STDIN_CLOBBER_LOCK.lock().unwrap();
+    let mut tty = OpenOptions::new().read(true).write(true).open("/dev/tty")?;
+
+    write!(tty, "Activate {name} ({summary})? [y/N] ")?;
+    tty.flush()?;
+
+    let mut answer = String::new();
+    BufReader::new(&tty).read_line(&mut answer)?;
+
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+impl ExecuteStep for Diff {
+    fn should_execute(&self, ctx: &Context) -> bool {
+        !matches!(ctx.diff, DiffMode::Disabled)
+            && matches!(ctx.goal, Goal::SwitchToConfiguration(..))
+    }
+
+    #[instrument(skip_all, name = "diff")]
+    async fn execute(&self, ctx: &mut Context<'_>) -> Result<(), HiveLibError> {
+        let built = ctx.state.build.as_ref().unwrap().trim();
+
+        let summary = match summarise(ctx, built).await {
+            Ok(summary) => {
+                info!("{name}: {summary}", name = ctx.name);
+                summary
+            }
+            Err(error) => {
+                warn!("Failed to diff {name}: {error}", name = ctx.name);
+                "diff unavailable".to_string()
+            }
+        };
+
+        if !matches!(ctx.diff, DiffMode::Confirm) {
+            return Ok(());
+        }
+
+        let name = ctx.name.clone();
+        let confirmed = tokio::task::spawn_blocking(move || prompt(&name, &summary))
+            .await
+            .unwrap()
+            .map_err(|error| HiveLibError::ActivationError(ActivationError::Prompt(error)))?;
+
+        if !confirmed {
+            return Err(HiveLibError::ActivationError(ActivationError::Declined(
+                ctx.name.clone(),
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_diff_closures() {
+        let output = "\
+linux: 6.6.1 → 6.12.3, +120.0 MiB
+\u{1b}[1mfirefox\u{1b}[0m: \u{1b}[31;1m130.0\u{1b}[0m → \u{1b}[32;1m131.0\u{1b}[0m, +2.1 MiB
+htop: ∅ → 3.3.0, +412.3 KiB
+nano: 8.0 → ∅, -2.9 MiB
+nixos-system-node: +1.2 KiB";
+
+        assert_eq!(
+            ClosureDiff::parse(output),
+            ClosureDiff {
+                added: vec!["htop: ∅ → 3.3.0, +412.3 KiB".to_string()],
+                removed: vec!["nano: 8.0 → ∅, -2.9 MiB".to_string()],
+                changed: vec![
+                    "linux: 6.6.1 → 6.12.3, +120.0 MiB".to_string(),
+                    "firefox: 130.0 → 131.0, +2.1 MiB".to_string(),
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn closure_sizes() {
+        let output = "\
+/nix/store/aaaa-nixos-system-node-25.05\t 2147483648
+/nix/store/bbbb-nixos-system-node-25.11    2273312768";
+
+        let sizes = parse_closure_sizes(output);
+
+        assert_eq!(
+            sizes.get("/nix/store/aaaa-nixos-system-node-25.05"),
+            Some(&2_147_483_648)
+        );
+        assert_eq!(
+            sizes.get("/nix/store/bbbb-nixos-system-node-25.11"),
+            Some(&2_273_312_768)
+        );
+    }
+
+    #[test]
+    fn size_delta() {
+        assert_eq!(format_size_delta(0), "+0.0 B");
+        assert_eq!(format_size_delta(512), "+512.0 B");
+        assert_eq!(format_size_delta(-1536), "-1.5 KiB");
+        assert_eq!(format_size_delta(120 * 1024 * 1024), "+120.0 MiB");
+        assert_eq!(format_size_delta(-3 * 1024 * 1024 * 1024), "-3.0 GiB");
+    }
+}
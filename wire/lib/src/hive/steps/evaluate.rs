@@ -21,7 +21,7 @@ impl Display for Evaluate {
 
 impl ExecuteStep for Evaluate {
     fn should_execute(&self, ctx: &Context) -> bool {
-        !matches!(ctx.goal, Goal::Keys)
+        !matches!(ctx.goal, Goal::Keys | Goal::Rollback(..)) && ctx.state.evaluation.is_none()
     }
 
     #[instrument(skip_all, name = "eval")]
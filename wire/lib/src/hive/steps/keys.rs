@@ -5,11 +5,15 @@ use base64::Engine;
 use base64::prelude::BASE64_STANDARD;
 use futures::future::join_all;
 use itertools::{Itertools, Position};
+use key_agent::keys::key_result::Status;
+use key_agent::keys::{KeyManifest, KeyResult, KeySpec, KeyState};
+use key_agent::{KEY_RESULT_PREFIX, KEY_STATE_PREFIX, PRUNE_ARGUMENT, QUERY_ARGUMENT};
 use owo_colors::OwoColorize;
 use prost::Message;
 use prost::bytes::BytesMut;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use std::collections::HashMap;
 use std::env;
 use std::fmt::Display;
 use std::io::Cursor;
@@ -21,11 +25,11 @@ use tokio::io::AsyncReadExt as _;
 use tokio::process::Command;
 use tokio::{fs::File, io::AsyncRead};
 use tokio_util::codec::LengthDelimitedCodec;
-use tracing::{debug, instrument};
+use tracing::{debug, info, instrument};
 
 use crate::HiveLibError;
 use crate::commands::common::push;
-use crate::commands::{CommandArguments, WireCommandChip, run_command};
+use crate::commands::{CommandArguments, Either, WireCommandChip, run_command};
 use crate::errors::KeyError;
 use crate::hive::node::{Context, ExecuteStep, Goal, Push, SwitchToConfigurationGoal};
 
@@ -56,6 +60,10 @@ pub struct Key {
     pub group: String,
     pub user: String,
     pub permissions: String,
+    #[serde(rename = "destDirPermissions")]
+    pub dest_dir_permissions: String,
+    #[serde(rename = "strictOwnership")]
+    pub strict_ownership: bool,
     pub source: Source,
     #[serde(rename = "uploadAt")]
     pub upload_at: UploadKeyAt,
@@ -85,8 +93,8 @@ impl Display for Key {
     }
 }
 
-fn get_u32_permission(key: &Key) -> Result<u32, KeyError> {
-    u32::from_str_radix(&key.permissions, 8).map_err(KeyError::ParseKeyPermissions)
+fn get_u32_permission(permissions: &str) -> Result<u32, KeyError> {
+    u32::from_str_radix(permissions, 8).map_err(KeyError::ParseKeyPermissions)
 }
 
 async fn create_reader(key: &'_ Key) -> Result<Pin<Box<dyn AsyncRead + Send + '_>>, KeyError> {
@@ -125,6 +133,15 @@ async fn create_reader(key: &'_ Key) -> Result<Pin<Box<dyn AsyncRead + Send + '_
     }
 }
 
+fn destination(key: &Key) -> String {
+    [key.dest_dir.clone(), key.name.clone()]
+        .iter()
+        .collect::<PathBuf>()
+        .into_os_string()
+        .into_string()
+        .unwrap()
+}
+
 async fn process_key(key: &Key) -> Result<(key_agent::keys::KeySpec, Vec<u8>), KeyError> {
     let mut reader = create_reader(key).await?;
 
@@ -135,9 +152,9 @@ async fn process_key(key: &Key) -> Result<(key_agent::keys::KeySpec, Vec<u8>), K
         .await
         .expect("failed to read into buffer");
 
-    let destination: PathBuf = [key.dest_dir.clone(), key.name.clone()].iter().collect();
+    let destination = destination(key);
 
-    debug!("Staging push to {}", destination.clone().display());
+    debug!("Staging push to {destination}");
 
     Ok((
         key_agent::keys::KeySpec {
@@ -147,15 +164,25 @@ async fn process_key(key: &Key) -> Result<(key_agent::keys::KeySpec, Vec<u8>), K
                 .expect("Failed to conver usize buf length to i32"),
             user: key.user.clone(),
             group: key.group.clone(),
-            permissions: get_u32_permission(key)?,
-            destination: destination.into_os_string().into_string().unwrap(),
+            permissions: get_u32_permission(&key.permissions)?,
+            directory_permissions: get_u32_permission(&key.dest_dir_permissions)?,
+            strict_ownership: key.strict_ownership,
+            destination,
             digest: Sha256::digest(&buf).to_vec(),
             last: false,
+            force: false,
         },
         buf,
     ))
 }
 
+/// Digest of the key's contents, in SRI format, without uploading it
+pub async fn key_digest(key: &Key) -> Result<String, KeyError> {
+    let (spec, _) = process_key(key).await?;
+
+    Ok(format!("sha256-{}", BASE64_STANDARD.encode(spec.digest)))
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Keys {
     pub filter: UploadKeyAt,
@@ -163,6 +190,17 @@ pub struct Keys {
 #[derive(Debug, PartialEq)]
 pub struct PushKeyAgent;
 
+impl Keys {
+    /// Stale keys are removed by the last keys step of a goal, once every
+    /// declared key was uploaded
+    const fn prunes(&self) -> bool {
+        matches!(
+            self.filter,
+            UploadKeyAt::NoFilter | UploadKeyAt::PostActivation
+        )
+    }
+}
+
 impl Display for Keys {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "Upload key @ {:?}", self.filter)
@@ -201,6 +239,183 @@ where
     }
 }
 
+/// Messages printed by the agent on lines starting with `prefix`
+fn decode_messages<M: Message + Default>(stdout: &str, prefix: &str) -> Vec<M> {
+    stdout
+        .lines()
+        .filter_map(|line| {
+            let frame = BASE64_STANDARD
+                .decode(line.trim().strip_prefix(prefix)?)
+                .ok()?;
+
+            M::decode_length_delimited(frame.as_slice()).ok()
+        })
+        .collect()
+}
+
+/// Runs the key agent, writing each frame to its stdin, and returns its
+/// stdout
+async fn run_key_agent(
+    ctx: &Context<'_>,
+    arguments: &[&str],
+    frames: Vec<Vec<u8>>,
+) -> Result<String, HiveLibError> {
+    let agent_directory = ctx.state.key_agent_directory.as_ref().unwrap();
+    let key_agent = format!("{agent_directory}/bin/key_agent");
+
+    let mut child = run_command(
+        &CommandArguments::from_argv(
+            std::iter::once(key_agent.as_str()).chain(arguments.iter().copied()),
+            ctx.modifiers,
+        )
+        .on_target(if ctx.should_apply_locally {
+            None
+        } else {
+            Some(&ctx.node.target)
+        })
+        .elevated(ctx.node)
+        .keep_stdin_open(),
+    )?;
+
+    let mut writer = SimpleLengthDelimWriter::new(async |data| child.write_stdin(data).await);
+
+    for frame in frames {
+        writer.send(BASE64_STANDARD.encode(frame).into()).await?;
+    }
+
+    match child
+        .wait_till_success()
+        .await
+        .map_err(HiveLibError::CommandError)?
+    {
+        Either::Left((_, stdout)) | Either::Right((_, stdout)) => Ok(stdout),
+    }
+}
+
+/// Asks the agent for the state of each key, and returns the results of the
+/// keys which are already in place
+async fn query_unchanged_keys(
+    ctx: &Context<'_>,
+    specs: &[&KeySpec],
+) -> Result<Vec<KeyResult>, HiveLibError> {
+    let frames = specs
+        .iter()
+        .with_position()
+        .map(|(position, spec)| {
+            KeySpec {
+                length: 0,
+                last: matches!(position, Position::Last | Position::Only),
+                ..(*spec).clone()
+            }
+            .encode_to_vec()
+        })
+        .collect();
+
+    let stdout = run_key_agent(ctx, &[QUERY_ARGUMENT], frames).await?;
+    let states = decode_messages::<KeyState>(&stdout, KEY_STATE_PREFIX);
+
+    Ok(unchanged_keys(specs, &states))
+}
+
+/// Has the agent remove the keys it previously wrote which are no longer
+/// declared by the node
+async fn prune_keys(ctx: &Context<'_>) -> Result<Vec<KeyResult>, HiveLibError> {
+    let manifest = KeyManifest {
+        destinations: ctx.node.keys.iter().map(destination).collect(),
+    };
+
+    let stdout = run_key_agent(ctx, &[PRUNE_ARGUMENT], vec![manifest.encode_to_vec()]).await?;
+
+    Ok(decode_messages::<KeyResult>(&stdout, KEY_RESULT_PREFIX))
+}
+
+fn unchanged_keys(specs: &[&KeySpec], states: &[KeyState]) -> Vec<KeyResult> {
+    states
+        .iter()
+        .filter(|state| {
+            state.metadata_matches
+                && specs.iter().any(|spec| {
+                    spec.destination == state.destination && spec.digest == state.digest
+                })
+        })
+        .map(|state| KeyResult {
+            destination: state.destination.clone(),
+            status: Status::Unchanged.into(),
+            reason: String::new(),
+            uid: state.uid,
+            gid: state.gid,
+            mode: state.mode,
+        })
+        .collect()
+}
+
+/// Logs what happened to each key, given as its destination and name, and
+/// to each stale key that was removed. Fails with every key the agent could
+/// not write or remove.
+fn report_keys(keys: &[(String, String)], results: &[KeyResult]) -> Result<(), HiveLibError> {
+    let declared = results
+        .iter()
+        .map(|result| (result.destination.as_str(), result))
+        .collect::<HashMap<_, _>>();
+
+    let mut errors = Vec::new();
+    let (mut changed, mut unchanged, mut removed) = (0, 0, 0);
+
+    for (destination, name) in keys {
+        let Some(result) = declared.get(destination.as_str()) else {
+            errors.push(HiveLibError::KeyError(
+                name.clone(),
+                KeyError::Rejected("the key agent did not report the key".to_string()),
+            ));
+            continue;
+        };
+
+        let owner = format!("{}:{} {:o}", result.uid, result.gid, result.mode);
+
+        match result.status() {
+            Status::Written => {
+                changed += 1;
+                info!("Wrote key {name} to {destination} ({owner})");
+            }
+            Status::Unchanged => {
+                unchanged += 1;
+                debug!("Key {name} at {destination} is unchanged ({owner})");
+            }
+            Status::Failed | Status::Removed => errors.push(HiveLibError::KeyError(
+                name.clone(),
+                KeyError::Rejected(result.reason.clone()),
+            )),
+        }
+    }
+
+    for result in results.iter().filter(|result| {
+        !keys
+            .iter()
+            .any(|(destination, _)| *destination == result.destination)
+    }) {
+        if result.status() == Status::Removed {
+            removed += 1;
+            info!("Removed stale key {}", result.destination);
+        } else {
+            errors.push(HiveLibError::KeyError(
+                result.destination.clone(),
+                KeyError::Rejected(result.reason.clone()),
+            ));
+        }
+    }
+
+    info!(
+        "Keys: {changed} changed, {unchanged} unchanged, {removed} removed, {} failed",
+        errors.len()
+    );
+
+    match errors.len() {
+        0 => Ok(()),
+        1 => Err(errors.remove(0)),
+        _ => Err(HiveLibError::KeysFailed(errors)),
+    }
+}
+
 impl ExecuteStep for Keys {
     fn should_execute(&self, ctx: &Context) -> bool {
         if ctx.no_keys {
@@ -221,8 +436,6 @@ impl ExecuteStep for Keys {
 
     #[instrument(skip_all, name = "keys")]
     async fn execute(&self, ctx: &mut Context<'_>) -> Result<(), HiveLibError> {
-        let agent_directory = ctx.state.key_agent_directory.as_ref().unwrap();
-
         let futures = ctx
             .node
             .keys
@@ -234,58 +447,71 @@ impl ExecuteStep for Keys {
             .map(|key| async move {
                 process_key(key)
                     .await
+                    .map(|processed| (key, processed))
                     .map_err(|err| HiveLibError::KeyError(key.name.clone(), err))
             });
 
-        let mut keys = join_all(futures)
+        let keys = join_all(futures)
             .await
             .into_iter()
-            .collect::<Result<Vec<_>, HiveLibError>>()?
-            .into_iter()
-            .peekable();
+            .collect::<Result<Vec<_>, HiveLibError>>()?;
+
+        let prune = self.prunes() && !ctx.no_prune_keys;
 
-        if keys.peek().is_none() {
+        if keys.is_empty() && !prune {
             debug!("Had no keys to push, ending KeyStep early.");
             return Ok(());
         }
 
-        let command_string = format!("{agent_directory}/bin/key_agent");
+        let names = keys
+            .iter()
+            .map(|(key, (spec, _))| (spec.destination.clone(), key.name.clone()))
+            .collect::<Vec<_>>();
+
+        let mut results = if ctx.force_keys || keys.is_empty() {
+            Vec::new()
+        } else {
+            query_unchanged_keys(
+                ctx,
+                &keys.iter().map(|(_, (spec, _))| spec).collect::<Vec<_>>(),
+            )
+            .await?
+        };
 
-        let mut child = run_command(
-            &CommandArguments::new(command_string, ctx.modifiers)
-                .on_target(if ctx.should_apply_locally {
-                    None
-                } else {
-                    Some(&ctx.node.target)
-                })
-                .elevated()
-                .keep_stdin_open()
-                .log_stdout(),
-        )?;
+        let changed = keys
+            .into_iter()
+            .filter(|(_, (spec, _))| {
+                !results
+                    .iter()
+                    .any(|result| result.destination == spec.destination)
+            })
+            .collect::<Vec<_>>();
 
-        let mut writer = SimpleLengthDelimWriter::new(async |data| child.write_stdin(data).await);
+        if !changed.is_empty() {
+            let mut frames = Vec::new();
 
-        for (position, (mut spec, buf)) in keys.with_position() {
-            if matches!(position, Position::Last | Position::Only) {
-                spec.last = true;
-            }
+            for (position, (_, (mut spec, buf))) in changed.into_iter().with_position() {
+                if matches!(position, Position::Last | Position::Only) {
+                    spec.last = true;
+                }
 
-            debug!("Writing spec & buf for {:?}", spec);
+                spec.force = ctx.force_keys;
 
-            writer
-                .send(BASE64_STANDARD.encode(spec.encode_to_vec()).into())
-                .await?;
-            writer.send(BASE64_STANDARD.encode(buf).into()).await?;
-        }
+                debug!("Writing spec & buf for {:?}", spec);
 
-        let status = child
-            .wait_till_success()
-            .await
-            .map_err(HiveLibError::CommandError)?;
+                frames.push(spec.encode_to_vec());
+                frames.push(buf);
+            }
 
-        debug!("status: {status:?}");
+            let stdout = run_key_agent(ctx, &[], frames).await?;
+            results.extend(decode_messages::<KeyResult>(&stdout, KEY_RESULT_PREFIX));
+        }
 
-        Ok(())
+        if prune {
+            results.extend(prune_keys(ctx).await?);
+        }
+
+        report_keys(&names, &results)
     }
 }
 
@@ -326,3 +552,141 @@ impl ExecuteStep for PushKeyAgent {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fmt::Write;
+
+    fn result(destination: &str, status: Status) -> KeyResult {
+        KeyResult {
+            destination: destination.to_string(),
+            status: status.into(),
+            reason: "user nginx does not exist".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parse_key_results() {
+        let written = result("/run/keys/a", Status::Written);
+        let failed = result("/etc/keys/b c", Status::Failed);
+
+        let stdout = [&written, &failed].iter().fold(
+            "unrelated output\n".to_string(),
+            |mut stdout, result| {
+                let _ = writeln!(
+                    stdout,
+                    "{KEY_RESULT_PREFIX}{}\r",
+                    BASE64_STANDARD.encode(result.encode_length_delimited_to_vec())
+                );
+                stdout
+            },
+        );
+
+        assert_eq!(
+            decode_messages::<KeyResult>(&stdout, KEY_RESULT_PREFIX),
+            vec![written, failed]
+        );
+    }
+
+    #[test]
+    fn unchanged() {
+        let spec = |destination: &str| KeySpec {
+            destination: destination.to_string(),
+            digest: vec![1, 2, 3],
+            ..Default::default()
+        };
+        let (a, b, c) = (
+            spec("/run/keys/a"),
+            spec("/run/keys/b"),
+            spec("/run/keys/c"),
+        );
+
+        let states = [
+            KeyState {
+                destination: "/run/keys/a".to_string(),
+                digest: vec![1, 2, 3],
+                metadata_matches: true,
+                uid: 1000,
+                ..Default::default()
+            },
+            // contents changed
+            KeyState {
+                destination: "/run/keys/b".to_string(),
+                digest: vec![4, 5, 6],
+                metadata_matches: true,
+                ..Default::default()
+            },
+            // owner or mode changed
+            KeyState {
+                destination: "/run/keys/c".to_string(),
+                digest: vec![1, 2, 3],
+                metadata_matches: false,
+                ..Default::default()
+            },
+        ];
+
+        assert_eq!(
+            unchanged_keys(&[&a, &b, &c], &states),
+            vec![KeyResult {
+                destination: "/run/keys/a".to_string(),
+                status: Status::Unchanged.into(),
+                uid: 1000,
+                ..Default::default()
+            }]
+        );
+    }
+
+    #[test]
+    fn report() {
+        let keys = [
+            ("/run/keys/a".to_string(), "a".to_string()),
+            ("/run/keys/b".to_string(), "b".to_string()),
+            ("/run/keys/c".to_string(), "c".to_string()),
+        ];
+
+        let results = [
+            result("/run/keys/a", Status::Written),
+            result("/run/keys/b", Status::Unchanged),
+        ];
+
+        assert!(report_keys(&keys[..2], &results).is_ok());
+
+        // stale keys are reported by their destination
+        let pruned = [
+            result("/run/keys/a", Status::Written),
+            result("/run/keys/b", Status::Unchanged),
+            result("/run/keys/old", Status::Removed),
+        ];
+        assert!(report_keys(&keys[..2], &pruned).is_ok());
+
+        let pruned = [
+            result("/run/keys/a", Status::Written),
+            result("/run/keys/b", Status::Unchanged),
+            result("/run/keys/old", Status::Failed),
+        ];
+        assert!(matches!(
+            report_keys(&keys[..2], &pruned),
+            Err(HiveLibError::KeyError(name, KeyError::Rejected(..))) if name == "/run/keys/old"
+        ));
+
+        // keys the agent never reported on fail
+        assert!(matches!(
+            report_keys(&keys, &results),
+            Err(HiveLibError::KeyError(name, KeyError::Rejected(..))) if name == "c"
+        ));
+
+        let results = [
+            result("/run/keys/a", Status::Failed),
+            result("/run/keys/b", Status::Unchanged),
+        ];
+
+        let Err(HiveLibError::KeysFailed(errors)) = report_keys(&keys, &results) else {
+            panic!("expected every failed key");
+        };
+
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].to_string(), "Failed to apply key a");
+    }
+}
@@ -3,7 +3,7 @@
 
 use std::fmt::Display;
 
-use tracing::{Level, event, instrument};
+use tracing::instrument;
 
 use crate::{
     HiveLibError,
@@ -26,29 +26,8 @@ impl ExecuteStep for Ping {
 
     #[instrument(skip_all, name = "ping")]
     async fn execute(&self, ctx: &mut Context<'_>) -> Result<(), HiveLibError> {
-        loop {
-            event!(
-                Level::INFO,
-                status = "attempting",
-                host = ctx.node.target.get_preferred_host()?.to_string()
-            );
-
-            if ctx.node.ping(ctx.modifiers).await.is_ok() {
-                event!(
-                    Level::INFO,
-                    status = "success",
-                    host = ctx.node.target.get_preferred_host()?.to_string()
-                );
-                return Ok(());
-            }
-
-            // ? will take us out if we ran out of hosts
-            event!(
-                Level::WARN,
-                status = "failed to ping",
-                host = ctx.node.target.get_preferred_host()?.to_string()
-            );
-            ctx.node.target.host_failed();
-        }
+        ctx.node.ping_with_failover(ctx.modifiers).await?;
+
+        Ok(())
     }
 }
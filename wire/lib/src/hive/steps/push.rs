@@ -30,7 +30,7 @@ impl Display for PushBuildOutput {
 
 impl ExecuteStep for PushEvaluatedOutput {
     fn should_execute(&self, ctx: &Context) -> bool {
-        !matches!(ctx.goal, Goal::Keys)
+        !matches!(ctx.goal, Goal::Keys | Goal::Rollback(..))
             && !ctx.should_apply_locally
             && (ctx.node.build_remotely | matches!(ctx.goal, Goal::Push))
     }
@@ -47,7 +47,7 @@ impl ExecuteStep for PushEvaluatedOutput {
 
 impl ExecuteStep for PushBuildOutput {
     fn should_execute(&self, ctx: &Context) -> bool {
-        if matches!(ctx.goal, Goal::Keys | Goal::Push) {
+        if matches!(ctx.goal, Goal::Keys | Goal::Push | Goal::Rollback(..)) {
             // skip if we are not building
             return false;
         }
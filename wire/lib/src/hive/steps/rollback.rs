@@ -0,0 +1,94 @@
+// SPDX-License-Identifier: AGPL-3.0-or-later
+// Copyright 2024-2025 wire Contributors
+
+use std::fmt::Display;
+
+use tracing::{info, instrument};
+
+use crate::{
+    HiveLibError,
+    commands::{CommandArguments, WireCommandChip, run_command},
+    errors::ActivationError,
+    hive::node::{Context, ExecuteStep, Goal, SwitchToConfigurationGoal},
+};
+
+#[derive(Debug, PartialEq)]
+pub struct Rollback;
+
+impl Display for Rollback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Roll back the node")
+    }
+}
+
+impl ExecuteStep for Rollback {
+    fn should_execute(&self, ctx: &Context) -> bool {
+        matches!(ctx.goal, Goal::Rollback(..))
+    }
+
+    #[instrument(skip_all, name = "rollback")]
+    async fn execute(&self, ctx: &mut Context<'_>) -> Result<(), HiveLibError> {
+        let Goal::Rollback(generation) = &ctx.goal else {
+            unreachable!("Cannot reach as guarded by should_execute")
+        };
+
+        let mut argv = vec![
+            "nix-env".to_string(),
+            "-p".to_string(),
+            "/nix/var/nix/profiles/system".to_string(),
+        ];
+
+        if let Some(generation) = generation {
+            info!("Switching system profile to generation {generation}");
+            argv.extend(["--switch-generation".to_string(), generation.to_string()]);
+        } else {
+            info!("Switching system profile to the previous generation");
+            argv.push("--rollback".to_string());
+        }
+
+        let target = if ctx.should_apply_locally {
+            None
+        } else {
+            Some(&ctx.node.target)
+        };
+
+        let child = run_command(
+            &CommandArguments::from_argv(argv, ctx.modifiers)
+                .mode(crate::commands::ChildOutputMode::Nix)
+                .on_target(target)
+                .elevated(ctx.node),
+        )?;
+
+        let _ = child
+            .wait_till_success()
+            .await
+            .map_err(HiveLibError::CommandError)?;
+
+        info!("Running switch-to-configuration switch");
+
+        let child = run_command(
+            &CommandArguments::from_argv(
+                [
+                    "/nix/var/nix/profiles/system/bin/switch-to-configuration",
+                    "switch",
+                ],
+                ctx.modifiers,
+            )
+            .on_target(target)
+            .elevated(ctx.node)
+            .log_stdout(),
+        )?;
+
+        child.wait_till_success().await.map_err(|error| {
+            HiveLibError::ActivationError(ActivationError::SwitchToConfigurationError(
+                SwitchToConfigurationGoal::Switch,
+                ctx.name.clone(),
+                error,
+            ))
+        })?;
+
+        info!("Rolled back {name}", name = ctx.name);
+
+        Ok(())
+    }
+}
@@ -24,6 +24,9 @@ pub mod errors;
 
 #[derive(Clone, Debug, Copy, Default)]
 pub enum StrictHostKeyChecking {
+    /// only accept hosts in `known_hosts`, used for nodes with pinned host keys
+    Yes,
+
     /// do not accept new host. dangerous!
     No,
 